@@ -0,0 +1,120 @@
+use actix_web::HttpRequest;
+use base64::prelude::*;
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::error::ApiError;
+use crate::utils::{Config, TokenConfig};
+
+// What a token is allowed to do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    // Upload images
+    Upload,
+    // Change tags and weights, move and rename images
+    Edit,
+    // Delete images
+    Delete,
+    // Everything, including metadata and library-wide actions
+    Admin,
+}
+
+// The token a request was authenticated with
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    scopes: Vec<Scope>,
+    // Categories the token may touch, empty for all of them
+    categories: Vec<String>,
+}
+
+impl Identity {
+    pub fn can(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    // Check that the token may touch images of a category
+    pub fn check_category(&self, category: &str) -> Result<(), ApiError> {
+        if self.categories.is_empty() || self.categories.iter().any(|c| c == category) {
+            Ok(())
+        } else {
+            warn!("Token {} is not allowed in category {}", self.name, category);
+            Err(ApiError::Forbidden("Token is not allowed in this category.".to_string()))
+        }
+    }
+}
+
+// Hash a token secret the way it is stored in the config
+pub fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+// Get the secret of a request, sent as Bearer <secret>
+fn bearer(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// Compare the hash of a secret with a configured one in constant time,
+// so the timing tells nothing about the secrets
+fn hash_matches(hash: &str, secret_hash: &str) -> bool {
+    bool::from(hash.as_bytes().ct_eq(secret_hash.to_lowercase().as_bytes()))
+}
+
+// Check that a request carries the secret of a hash, for endpoints outside of the tokens
+pub fn bearer_matches(req: &HttpRequest, secret_hash: &str) -> bool {
+    bearer(req).is_some_and(|secret| hash_matches(&hash_secret(secret), secret_hash))
+}
+
+// Find the token of a request, should be Bearer <secret>
+pub fn authenticate(req: &HttpRequest, config: &Config) -> Option<Identity> {
+    let secret = bearer(req)?;
+    let hash = hash_secret(secret);
+    // Compare with every token, not only up to the first match
+    let mut found: Option<&TokenConfig> = None;
+    for token in &config.tokens {
+        if hash_matches(&hash, &token.secret_hash) {
+            found = Some(token);
+        }
+    }
+    if let Some(token) = found {
+        if token.expires.is_some_and(|expires| expires <= Utc::now()) {
+            warn!("Token {} has expired", token.name);
+            return None;
+        }
+        return Some(Identity {
+            name: token.name.clone(),
+            scopes: token.scopes.clone(),
+            categories: token.categories.clone(),
+        });
+    }
+    // The shared password keeps working as an admin token, sent base64 encoded
+    let legacy = BASE64_STANDARD.encode(config.pwd.as_bytes());
+    if !config.pwd.is_empty() && bool::from(secret.as_bytes().ct_eq(legacy.as_bytes())) {
+        return Some(Identity {
+            name: "pwd".to_string(),
+            scopes: vec![Scope::Admin],
+            categories: Vec::new(),
+        });
+    }
+    None
+}
+
+// Authenticate a request and check that its token has the scope
+pub fn authorize(req: &HttpRequest, config: &Config, scope: Scope) -> Result<Identity, ApiError> {
+    let identity = match authenticate(req, config) {
+        Some(identity) => identity,
+        None => return Err(ApiError::Unauthorized),
+    };
+    if !identity.can(scope) {
+        warn!("Token {} lacks the {:?} scope", identity.name, scope);
+        return Err(ApiError::Forbidden("Token lacks the required scope.".to_string()));
+    }
+    Ok(identity)
+}
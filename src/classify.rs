@@ -0,0 +1,85 @@
+use log::{error, info, warn};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::index::{file_name, ImageIndex};
+use crate::metadata::MetadataStore;
+use crate::utils::{Category, Config};
+
+// Upload target that picks the category of each image from its aspect ratio
+pub const AUTO_CATEGORY: &str = "auto";
+
+// Get the first category, in the configured order, whose aspect ratio rules accept the image
+pub fn classify(categories: &[Category], width: u32, height: u32) -> Option<&Category> {
+    categories.iter().find(|c| c.accepts(width, height))
+}
+
+// An image moved to another category by reclassify
+#[derive(Serialize, Debug)]
+pub struct Move {
+    pub filename: String,
+    pub from: String,
+    pub to: String,
+}
+
+// Move the images of the categories with rules to the category the rules pick for them,
+// images that no category accepts and images of categories without rules are left alone
+pub fn reclassify(
+    config: &Config,
+    index: &RwLock<ImageIndex>,
+    store: &MetadataStore,
+    dry_run: bool,
+) -> Vec<Move> {
+    // Plan under a read lock, so serving and uploads go on while the files are moved
+    let mut planned = Vec::new();
+    {
+        let index = index.read().unwrap();
+        for category in config.categories.iter().filter(|c| c.has_rules()) {
+            for path in index.category(&category.name).unwrap_or_default() {
+                let filename = file_name(path);
+                let target = index
+                    .dimensions(filename)
+                    .and_then(|(width, height)| classify(&config.categories, width, height));
+                if let Some(target) = target.filter(|target| target.name != category.name) {
+                    planned.push((path.clone(), category.name.clone(), target));
+                }
+            }
+        }
+    }
+
+    let mut moves = Vec::new();
+    for (path, from, target) in planned {
+        let filename = file_name(&path).to_string();
+        let new_path = Path::new(&config.image_folder)
+            .join(&target.folder)
+            .join(&filename);
+        if !dry_run {
+            if new_path.exists() {
+                warn!("Not moving {}: {:?} already exists", path, new_path);
+                continue;
+            }
+            {
+                // Hold the index for each move so the watcher sees it as already done
+                let mut index = index.write().unwrap();
+                if let Err(e) = fs::rename(&path, &new_path) {
+                    error!("Failed to move {} to {:?}: {}", path, new_path, e);
+                    continue;
+                }
+                index.move_images(&[(path, new_path)]);
+            }
+            // Only the category changes, the content and its hashes stay the same
+            if let Err(e) = store.rename(&filename, &filename, &target.name, false) {
+                error!("Failed to store the category of {}: {}", filename, e);
+            }
+            info!("Moved {} from {} to {}", filename, from, target.name);
+        }
+        moves.push(Move {
+            filename,
+            from,
+            to: target.name.clone(),
+        });
+    }
+    moves
+}
@@ -0,0 +1,265 @@
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::utils::{ClientIpConfig, Config};
+
+// Headers a trusted proxy may use to pass on the address of the client
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    // Cloudflare
+    CfConnectingIp,
+    // Comma separated list of addresses, each proxy appends the one it got the request from
+    XForwardedFor,
+    // RFC 7239, e.g. for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"
+    Forwarded,
+    // nginx
+    XRealIp,
+}
+
+impl ForwardedHeader {
+    fn name(self) -> &'static str {
+        match self {
+            ForwardedHeader::CfConnectingIp => "CF-Connecting-IP",
+            ForwardedHeader::XForwardedFor => "X-Forwarded-For",
+            ForwardedHeader::Forwarded => "Forwarded",
+            ForwardedHeader::XRealIp => "X-Real-IP",
+        }
+    }
+
+    // Get the addresses in the header, the closest hop last
+    fn addresses(self, value: &str) -> Vec<IpAddr> {
+        match self {
+            ForwardedHeader::CfConnectingIp | ForwardedHeader::XRealIp => {
+                parse_ip(value).into_iter().collect()
+            }
+            ForwardedHeader::XForwardedFor => value.split(',').filter_map(parse_ip).collect(),
+            ForwardedHeader::Forwarded => value
+                .split(',')
+                .flat_map(|element| element.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .filter(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .filter_map(|(_, node)| parse_ip(node))
+                .collect(),
+        }
+    }
+}
+
+// Parse an address as proxies write it: with or without quotes, brackets and a port
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .and_then(|v| v.parse().ok())
+}
+
+// The client of a request as far as it can be trusted, the headers set by proxies are only
+// honoured when the request comes from one of the trusted proxies
+#[derive(Debug, Clone, Default)]
+pub struct ClientIp {
+    pub ip: Option<IpAddr>,
+    // Country reported by Cloudflare in CF-IPCountry
+    pub country: Option<String>,
+}
+
+impl ClientIp {
+    // Resolve the client of a request with the config of the app
+    pub fn of(req: &HttpRequest) -> ClientIp {
+        match req.app_data::<web::Data<Config>>() {
+            Some(config) => ClientIp::resolve(req, &config.client_ip),
+            None => ClientIp::resolve(req, &ClientIpConfig::default()),
+        }
+    }
+
+    fn resolve(req: &HttpRequest, config: &ClientIpConfig) -> ClientIp {
+        let peer = match req.peer_addr() {
+            Some(addr) => addr.ip(),
+            None => return ClientIp::default(),
+        };
+        // The country comes from Cloudflare itself, never from the client
+        let country = req
+            .headers()
+            .get("CF-IPCountry")
+            .filter(|_| config.is_cloudflare(peer))
+            .and_then(|country| country.to_str().ok())
+            .map(str::to_string);
+        let header = match config.header {
+            Some(header) if config.trusts(peer) => header,
+            _ => {
+                return ClientIp {
+                    ip: Some(peer),
+                    country,
+                }
+            }
+        };
+        let addresses: Vec<IpAddr> = req
+            .headers()
+            .get_all(header.name())
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| header.addresses(value))
+            .collect();
+        ClientIp {
+            ip: Some(client_of(&addresses, config).unwrap_or(peer)),
+            country,
+        }
+    }
+}
+
+// Walk back from the closest hop, the first address that is not one of our proxies is the
+// client, anything before it may be made up by the client
+fn client_of(addresses: &[IpAddr], config: &ClientIpConfig) -> Option<IpAddr> {
+    addresses
+        .iter()
+        .rev()
+        .find(|&&ip| !config.trusts(ip))
+        .or(addresses.first())
+        .copied()
+}
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{}", ip),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(ClientIp::of(req)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn config(header: Option<ForwardedHeader>) -> ClientIpConfig {
+        ClientIpConfig {
+            trusted_proxies: vec!["127.0.0.0/8".parse().unwrap(), "10.0.0.0/8".parse().unwrap()],
+            header,
+            cloudflare_proxies: vec!["173.245.48.0/20".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn parse_ip_accepts_proxy_notations() {
+        assert_eq!(parse_ip(" 192.0.2.60 "), Some(ip("192.0.2.60")));
+        assert_eq!(parse_ip("192.0.2.60:8080"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_ip("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_ip("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_ip("\"[2001:db8::1]:4711\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_ip("unknown"), None);
+        assert_eq!(parse_ip("_hidden"), None);
+        assert_eq!(parse_ip(""), None);
+    }
+
+    #[test]
+    fn x_forwarded_for_lists_every_hop() {
+        let header = ForwardedHeader::XForwardedFor;
+        let addresses = header.addresses("203.0.113.7, 198.51.100.2,10.0.0.1");
+        assert_eq!(addresses, vec![ip("203.0.113.7"), ip("198.51.100.2"), ip("10.0.0.1")]);
+        let addresses = header.addresses("garbage, 198.51.100.2");
+        assert_eq!(addresses, vec![ip("198.51.100.2")]);
+    }
+
+    #[test]
+    fn forwarded_reads_quoted_and_bracketed_nodes() {
+        let value = "for=192.0.2.43;proto=https, For=\"[2001:db8:cafe::17]:4711\";by=10.0.0.1, \
+            for=unknown, for=\"198.51.100.17:80\"";
+        let addresses = ForwardedHeader::Forwarded.addresses(value);
+        assert_eq!(
+            addresses,
+            vec![ip("192.0.2.43"), ip("2001:db8:cafe::17"), ip("198.51.100.17")]
+        );
+    }
+
+    #[test]
+    fn single_address_headers() {
+        let addresses = ForwardedHeader::CfConnectingIp.addresses("203.0.113.7");
+        assert_eq!(addresses, vec![ip("203.0.113.7")]);
+        assert!(ForwardedHeader::XRealIp.addresses("1.2.3.4, 5.6.7.8").is_empty());
+    }
+
+    #[test]
+    fn walk_stops_at_the_first_untrusted_hop() {
+        let config = config(Some(ForwardedHeader::XForwardedFor));
+        // The client made up the first address, the proxies appended the rest
+        let hops = [ip("6.6.6.6"), ip("203.0.113.7"), ip("10.0.0.2"), ip("10.0.0.1")];
+        assert_eq!(client_of(&hops, &config), Some(ip("203.0.113.7")));
+        // Only proxies, the farthest one is the best guess
+        assert_eq!(client_of(&[ip("10.0.0.2"), ip("10.0.0.1")], &config), Some(ip("10.0.0.2")));
+        assert_eq!(client_of(&[], &config), None);
+    }
+
+    #[test]
+    fn headers_are_ignored_without_a_configured_header() {
+        let req = TestRequest::default()
+            .peer_addr("127.0.0.1:1234".parse().unwrap())
+            .insert_header(("CF-Connecting-IP", "1.2.3.4"))
+            .insert_header(("X-Forwarded-For", "1.2.3.4"))
+            .insert_header(("CF-IPCountry", "NL"))
+            .to_http_request();
+        let client = ClientIp::resolve(&req, &config(None));
+        assert_eq!(client.ip, Some(ip("127.0.0.1")));
+        assert_eq!(client.country, None);
+    }
+
+    #[test]
+    fn only_the_configured_header_of_a_trusted_peer_is_used() {
+        let config = config(Some(ForwardedHeader::XForwardedFor));
+        let req = TestRequest::default()
+            .peer_addr("127.0.0.1:1234".parse().unwrap())
+            .insert_header(("CF-Connecting-IP", "1.2.3.4"))
+            .insert_header(("X-Forwarded-For", "6.6.6.6, 203.0.113.7"))
+            .to_http_request();
+        assert_eq!(ClientIp::resolve(&req, &config).ip, Some(ip("203.0.113.7")));
+
+        let req = TestRequest::default()
+            .peer_addr("198.51.100.9:1234".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.7"))
+            .to_http_request();
+        assert_eq!(ClientIp::resolve(&req, &config).ip, Some(ip("198.51.100.9")));
+    }
+
+    #[test]
+    fn country_is_only_read_from_cloudflare() {
+        let config = config(Some(ForwardedHeader::CfConnectingIp));
+        let req = TestRequest::default()
+            .peer_addr("127.0.0.1:1234".parse().unwrap())
+            .insert_header(("CF-IPCountry", "NL"))
+            .to_http_request();
+        assert_eq!(ClientIp::resolve(&req, &config).country, None);
+
+        let req = TestRequest::default()
+            .peer_addr("173.245.48.1:1234".parse().unwrap())
+            .insert_header(("CF-Connecting-IP", "203.0.113.7"))
+            .insert_header(("CF-IPCountry", "NL"))
+            .to_http_request();
+        let client = ClientIp::resolve(&req, &config);
+        // Cloudflare is not in the trusted proxies, so its address is the client
+        assert_eq!(client.ip, Some(ip("173.245.48.1")));
+        assert_eq!(client.country.as_deref(), Some("NL"));
+    }
+}
@@ -0,0 +1,126 @@
+use actix_multipart::MultipartError;
+use actix_web::error::BlockingError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use log::error;
+use std::fmt;
+
+use crate::request_id;
+
+// Errors of the API, every one is answered with the same JSON shape:
+// {"code": "not_found", "message": "Image not found.", "request_id": "..."}
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    Unprocessable(String),
+    // Seconds the client should wait, sent in Retry-After
+    TooManyRequests(u64),
+    // The cause is logged, clients only get the message
+    Internal(String),
+}
+
+impl ApiError {
+    // Stable identifier of the kind of error, for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::Unprocessable(_) => "unprocessable_entity",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::Unauthorized => "Unauthorized.",
+            ApiError::TooManyRequests(_) => "Too many requests.",
+            ApiError::BadRequest(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::UnsupportedMediaType(message)
+            | ApiError::Unprocessable(message)
+            | ApiError::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        if let ApiError::TooManyRequests(retry_after) = self {
+            res.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+        res.json(serde_json::json!({
+            "code": self.code(),
+            "message": self.message(),
+            "request_id": request_id::current(),
+        }))
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        error!("I/O error: {}", e);
+        ApiError::Internal("Internal server error.".to_string())
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> Self {
+        error!("Metadata store error: {}", e);
+        ApiError::Internal("Internal server error.".to_string())
+    }
+}
+
+impl From<BlockingError> for ApiError {
+    fn from(e: BlockingError) -> Self {
+        error!("Blocking task failed: {}", e);
+        ApiError::Internal("Internal server error.".to_string())
+    }
+}
+
+impl From<MultipartError> for ApiError {
+    fn from(e: MultipartError) -> Self {
+        ApiError::BadRequest(format!("Invalid multipart body: {}", e))
+    }
+}
+
+// Answer the errors of the query, path and JSON extractors in the same shape
+pub fn bad_request(e: impl fmt::Display) -> actix_web::Error {
+    ApiError::BadRequest(e.to_string()).into()
+}
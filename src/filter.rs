@@ -0,0 +1,160 @@
+use serde::Deserialize;
+
+use crate::index::{file_name, ImageIndex};
+
+// Tolerance of an aspect ratio given without one
+const DEFAULT_ASPECT_TOLERANCE: f64 = 0.01;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    Landscape,
+    Portrait,
+    Square,
+}
+
+impl Orientation {
+    pub fn of(width: u32, height: u32) -> Orientation {
+        match width.cmp(&height) {
+            std::cmp::Ordering::Greater => Orientation::Landscape,
+            std::cmp::Ordering::Less => Orientation::Portrait,
+            std::cmp::Ordering::Equal => Orientation::Square,
+        }
+    }
+}
+
+// Aspect ratio with a relative tolerance, written as 16:9~0.05
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aspect {
+    pub ratio: f64,
+    pub tolerance: f64,
+}
+
+impl Aspect {
+    pub fn parse(aspect: &str) -> Result<Aspect, String> {
+        let invalid = || format!("Invalid aspect ratio '{}', expected e.g. 16:9~0.05.", aspect);
+        let (ratio, tolerance) = match aspect.split_once('~') {
+            Some((ratio, tolerance)) => (ratio, tolerance.trim().parse().map_err(|_| invalid())?),
+            None => (aspect, DEFAULT_ASPECT_TOLERANCE),
+        };
+        // Either width:height or a plain ratio
+        let ratio: f64 = match ratio.split_once(':') {
+            Some((w, h)) => {
+                let w: f64 = w.trim().parse().map_err(|_| invalid())?;
+                let h: f64 = h.trim().parse().map_err(|_| invalid())?;
+                w / h
+            }
+            None => ratio.trim().parse().map_err(|_| invalid())?,
+        };
+        if !ratio.is_finite() || ratio <= 0.0 || !(0.0..1.0).contains(&tolerance) {
+            return Err(invalid());
+        }
+        Ok(Aspect { ratio, tolerance })
+    }
+
+    pub fn matches(&self, width: u32, height: u32) -> bool {
+        let ratio = f64::from(width) / f64::from(height);
+        (ratio / self.ratio - 1.0).abs() <= self.tolerance
+    }
+}
+
+// Filters on the images served by the list and random endpoints
+#[derive(Debug, Default, Clone)]
+pub struct ImageFilter {
+    // Tags an image must all have
+    pub tags: Vec<String>,
+    // Tags an image must not have
+    pub exclude_tags: Vec<String>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub orientation: Option<Orientation>,
+    pub aspect: Option<Aspect>,
+}
+
+impl ImageFilter {
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.exclude_tags.is_empty() && !self.by_dimensions()
+    }
+
+    fn by_dimensions(&self) -> bool {
+        self.min_width.is_some()
+            || self.min_height.is_some()
+            || self.orientation.is_some()
+            || self.aspect.is_some()
+    }
+
+    // Check if an indexed image passes the filter
+    pub fn matches(&self, index: &ImageIndex, path: &str) -> bool {
+        let filename = file_name(path);
+        let tags = index.tags(filename);
+        let has = |tag: &String| tags.is_some_and(|tags| tags.contains(tag));
+        if !self.tags.iter().all(has) || self.exclude_tags.iter().any(has) {
+            return false;
+        }
+        if !self.by_dimensions() {
+            return true;
+        }
+        // Images whose dimensions could not be read never pass a dimension filter
+        let (width, height) = match index.dimensions(filename) {
+            Some(dimensions) => dimensions,
+            None => return false,
+        };
+        self.min_width.is_none_or(|min| width >= min)
+            && self.min_height.is_none_or(|min| height >= min)
+            && self.orientation.is_none_or(|o| o == Orientation::of(width, height))
+            && self.aspect.is_none_or(|aspect| aspect.matches(width, height))
+    }
+
+    // Describe the filter, images picked under different filters are kept apart
+    pub fn key(&self) -> String {
+        format!(
+            "{}|{}|{:?}|{:?}|{:?}|{:?}",
+            self.tags.join(","),
+            self.exclude_tags.join(","),
+            self.min_width,
+            self.min_height,
+            self.orientation,
+            self.aspect.map(|a| (a.ratio, a.tolerance))
+        )
+    }
+}
+
+// Query parameters filtering the images of /api/list/{category}
+#[derive(Deserialize, Debug, Default)]
+pub struct FilterQuery {
+    // Comma separated tags the images must all have, 'tag' is an alias
+    #[serde(alias = "tag")]
+    pub tags: Option<String>,
+    // Comma separated tags the images must not have
+    pub exclude_tags: Option<String>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub orientation: Option<Orientation>,
+    // Aspect ratio like 16:9, optionally with a relative tolerance like 16:9~0.05
+    pub aspect: Option<String>,
+}
+
+impl FilterQuery {
+    pub fn filter(&self) -> Result<ImageFilter, String> {
+        Ok(ImageFilter {
+            tags: self.tags.as_deref().map(parse_tags).unwrap_or_default(),
+            exclude_tags: self.exclude_tags.as_deref().map(parse_tags).unwrap_or_default(),
+            min_width: self.min_width,
+            min_height: self.min_height,
+            orientation: self.orientation,
+            aspect: self.aspect.as_deref().map(Aspect::parse).transpose()?,
+        })
+    }
+}
+
+// Split a comma separated list of tags, tags are trimmed and lowercase
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .split(',')
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
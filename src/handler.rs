@@ -1,274 +1,1151 @@
 use actix_multipart::Multipart;
-use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
 use futures::{StreamExt, TryStreamExt};
-use image::{io::Reader as ImageReader, ImageFormat};
-use md5::{Digest, Md5};
-use rand::Rng;
+use log::{error, info, warn};
+use image::io::{Limits, Reader as ImageReader};
+use image::{DynamicImage, ImageError, ImageFormat, ImageResult};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use serde_json::Value;
+use std::collections::BTreeSet;
 use std::fs;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 
+use crate::auth::*;
+use crate::client_ip::*;
+use crate::classify::*;
+use crate::error::ApiError;
+use crate::filter::*;
+use crate::http_cache::*;
+use crate::index::*;
+use crate::metadata::*;
+use crate::metrics::METRICS;
+use crate::phash::*;
+use crate::random::*;
+use crate::rendition::*;
+use crate::stats::*;
 use crate::utils::*;
 
 
 // Get the specified image
-#[actix_web::get("/api/image/{filename}")]
+#[actix_web::route("/api/image/{filename}", method = "GET", method = "HEAD")]
+#[allow(clippy::too_many_arguments)]
 pub async fn get_image(
     filename: web::Path<String>,
-    data: web::Data<Vec<Vec<String>>>,
+    query: web::Query<ImageQuery>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    etags: web::Data<EtagCache>,
+    store: web::Data<MetadataStore>,
+    stats: web::Data<ViewStats>,
     req: HttpRequest,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let filename = filename.into_inner();
-    let pc_images = &data[0];
-    let mp_images = &data[1];
-    let file_path = pc_images
-        .iter()
-        .chain(mp_images.iter())
-        .find(|&path| {
-            Path::new(path)
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string()
-                == filename
-        })
-        .map(|path| path);
-    // Get the visitor's ip address and print to log
-    let ip_str = if let Some(cf_ip) = req.headers().get("CF-Connecting-IP") {
-        cf_ip.to_str().unwrap_or("").to_string() // Convert to String
-    } else if let Some(peer_addr) = req.peer_addr() {
-        peer_addr.ip().to_string()
-    } else {
-        "".to_string() // Could not get the ip address
+    let (file_path, category) = {
+        let index = index.read().unwrap();
+        let file_path = index.find(&filename).cloned();
+        let category = file_path
+            .as_ref()
+            .and_then(|path| index.category_of(Path::new(path)))
+            .map(str::to_string);
+        (file_path, category)
     };
 
-    // Get the visitor's country and print to log
-    let country = if let Some(cf_country) = req.headers().get("CF-IPCountry") {
-        cf_country.to_str().unwrap_or("Unknown country").to_string()
+    if let Some(file_path) = file_path {
+        // Validate the requested size before creating a rendition
+        let mut query = query.into_inner();
+        if let Err(e) = query.validate(&config.renditions) {
+            return Err(ApiError::BadRequest(e));
+        }
+        query.snap(&config.renditions);
+        let format = OutputFormat::negotiate(&req);
+        let cache_folder = config.renditions.cache_folder.clone();
+        let file_path = match image_file(file_path, query, format, cache_folder).await {
+            Ok(path) => path,
+            // The file may have vanished since it was indexed
+            Err(e) if is_not_found(&e) => {
+                return Err(ApiError::NotFound("Image not found.".to_string()))
+            }
+            Err(e) => {
+                error!("Failed to create rendition of {}: {}", filename, e);
+                return Err(ApiError::Internal("Failed to create rendition.".to_string()));
+            }
+        };
+        match serve_file(&req, &file_path, format.mime(), &config.cache_control.image, &etags) {
+            Ok(response) => {
+                // Ranges of a file are parts of one view
+                let viewed = matches!(response.status(), StatusCode::OK | StatusCode::NOT_MODIFIED);
+                if config.stats.enabled && viewed && req.method() == Method::GET {
+                    let category = category.as_deref().unwrap_or_default();
+                    let country = ClientIp::of(&req).country;
+                    stats.record_view(&filename, category, country.as_deref());
+                }
+                Ok(response)
+            }
+            // The file may have vanished since it was indexed
+            Err(e) => {
+                error!("Failed to read {:?}: {}", file_path, e);
+                Err(ApiError::NotFound("Image not found.".to_string()))
+            }
+        }
+    } else if let Ok(Some(target)) = store.redirect(&filename) {
+        // The image was renamed, send the client to its new name with the same query
+        let location = match req.query_string() {
+            "" => format!("/api/image/{}", target),
+            query => format!("/api/image/{}?{}", target, query),
+        };
+        Ok(HttpResponse::MovedPermanently()
+            .insert_header((header::LOCATION, location))
+            .finish())
     } else {
-        "Unknown country".to_string()
+        Err(ApiError::NotFound("Image not found.".to_string()))
+    }
+}
+
+// Get the file to serve for an image, creating a rendition if a size or another format is requested
+async fn image_file(
+    file_path: String,
+    query: ImageQuery,
+    format: OutputFormat,
+    cache_folder: String,
+) -> ImageResult<PathBuf> {
+    if !query.is_resized() && format == OutputFormat::WebP {
+        return Ok(PathBuf::from(file_path));
+    }
+    web::block(move || create_rendition(Path::new(&file_path), &query, format, &cache_folder))
+        .await
+        .unwrap_or_else(|e| Err(ImageError::IoError(std::io::Error::other(e.to_string()))))
+}
+
+// Delete an image with its thumbnail, renditions and metadata
+#[actix_web::delete("/api/image/{filename}")]
+pub async fn delete_image(
+    filename: web::Path<String>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    store: web::Data<MetadataStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let identity = authorize(&req, &config, Scope::Delete)?;
+    let filename = filename.into_inner();
+    // Hold the index so the watcher sees the removal as already done
+    let mut index = index.write().unwrap();
+    let file_path = match index.find(&filename) {
+        Some(path) => PathBuf::from(path),
+        None => return Err(ApiError::NotFound("Image not found.".to_string())),
+    };
+    identity.check_category(index.category_of(&file_path).unwrap_or_default())?;
+    // The file may have vanished before the watcher noticed
+    if let Err(e) = fs::remove_file(&file_path) {
+        if e.kind() == ErrorKind::NotFound {
+            index.remove(&file_path);
+            return Err(ApiError::NotFound("Image not found.".to_string()));
+        }
+        return Err(e.into());
+    }
+    index.remove(&file_path);
+    index.set_tags(&filename, BTreeSet::new());
+    // Drop the weight so the sidecar file does not keep a stale entry
+    if index.weights().contains_key(&filename) {
+        index.set_weight(&filename, 1.0);
+        write_weights(&config.image_folder, index.weights())?;
+    }
+    if let Err(e) = remove_thumbnail(&file_path, &config.image_folder) {
+        error!("Failed to remove thumbnail for {:?}: {}", file_path, e);
+    }
+    if let Err(e) = remove_renditions(&file_path, &config.renditions.cache_folder) {
+        error!("Failed to remove renditions for {:?}: {}", file_path, e);
+    }
+    if let Err(e) = store.remove(&filename) {
+        error!("Failed to remove metadata of {}: {}", filename, e);
+    }
+    info!("Deleted {:?} with token {}", file_path, identity.name);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub struct MoveBody {
+    // Category to move the image to
+    pub category: Option<String>,
+    // New public name of the image, the .webp extension is added if missing
+    pub filename: Option<String>,
+    // Redirect the old URL to the new one
+    #[serde(default)]
+    pub redirect: bool,
+}
+
+// Check that a name is a plain filename, it ends up in paths and URLs
+fn valid_filename(filename: &str) -> bool {
+    !filename.is_empty()
+        && !filename.starts_with('.')
+        && filename
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+// Move an image to another category and/or rename it
+#[actix_web::patch("/api/image/{filename}")]
+pub async fn move_image(
+    filename: web::Path<String>,
+    body: web::Json<MoveBody>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    store: web::Data<MetadataStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let identity = authorize(&req, &config, Scope::Edit)?;
+    let filename = filename.into_inner();
+    let new_filename = match &body.filename {
+        Some(name) if name.ends_with(".webp") => name.clone(),
+        Some(name) => format!("{}.webp", name),
+        None => filename.clone(),
     };
+    if !valid_filename(&new_filename) {
+        return Err(ApiError::BadRequest(
+            "Filenames may only contain letters, digits, '.', '-' and '_'.".to_string(),
+        ));
+    }
 
-    println!(
-        "Visitor IP: {}, Country: {}, file: {}",
-        ip_str, country, filename
-    );
-    
-    if let Some(file_path) = file_path {
-        let mut file = File::open(file_path).unwrap();
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
-        HttpResponse::Ok().content_type("image/jpeg").body(buffer)
-    } else {
-        HttpResponse::NotFound().json(Value::String("Image not found.".to_string()))
+    // Hold the index so the watcher sees the move as already done
+    let mut index = index.write().unwrap();
+    let file_path = match index.find(&filename) {
+        Some(path) => PathBuf::from(path),
+        None => return Err(ApiError::NotFound("Image not found.".to_string())),
+    };
+    let current = index.category_of(&file_path).unwrap_or_default().to_string();
+    let category = match &body.category {
+        Some(name) => match config.categories.iter().find(|c| c.name == *name) {
+            Some(category) => category,
+            None => return Err(ApiError::NotFound("Invalid category.".to_string())),
+        },
+        None => match config.categories.iter().find(|c| c.name == current) {
+            Some(category) => category,
+            None => return Err(ApiError::NotFound("Invalid category.".to_string())),
+        },
+    };
+    identity.check_category(&current)?;
+    identity.check_category(&category.name)?;
+    let renamed = new_filename != filename;
+    if renamed && index.find(&new_filename).is_some() {
+        return Err(ApiError::Conflict("An image with that filename already exists.".to_string()));
+    }
+    let new_path = Path::new(&config.image_folder)
+        .join(&category.folder)
+        .join(&new_filename);
+    if new_path == file_path {
+        return Ok(HttpResponse::Ok().json(serde_json::json!({
+            "filename": new_filename,
+            "category": category.name,
+            "url": format!("/api/image/{}", new_filename),
+        })));
+    }
+
+    fs::create_dir_all(new_path.parent().unwrap_or(Path::new(".")))?;
+    if let Err(e) = fs::rename(&file_path, &new_path) {
+        // The target folder missing is a server error, the image missing is not
+        if e.kind() == ErrorKind::NotFound && !file_path.exists() {
+            return Err(ApiError::NotFound("Image not found.".to_string()));
+        }
+        return Err(e.into());
+    }
+    index.remove(&file_path);
+    index.insert(&new_path);
+    if renamed {
+        // Carry the tags and the weight over to the new name
+        let tags = index.tags(&filename).cloned().unwrap_or_default();
+        index.set_tags(&filename, BTreeSet::new());
+        index.set_tags(&new_filename, tags);
+        if let Some(weight) = index.weights().get(&filename).copied() {
+            index.set_weight(&filename, 1.0);
+            index.set_weight(&new_filename, weight);
+            write_weights(&config.image_folder, index.weights())?;
+        }
+        let thumbnails = Path::new(&config.image_folder).join("thumbnails");
+        if let Err(e) = fs::rename(thumbnails.join(&filename), thumbnails.join(&new_filename)) {
+            error!("Failed to rename thumbnail of {}: {}", filename, e);
+            if let Err(e) = create_thumbnail(&new_path, 200, 200, &config.image_folder) {
+                error!("Failed to create thumbnail for {:?}: {}", new_path, e);
+            }
+        }
+        if let Err(e) = remove_renditions(&file_path, &config.renditions.cache_folder) {
+            error!("Failed to remove renditions for {:?}: {}", file_path, e);
+        }
     }
+    if let Err(e) = store.rename(&filename, &new_filename, &category.name, body.redirect) {
+        error!("Failed to move metadata of {}: {}", filename, e);
+    }
+    info!("Moved {:?} to {:?} with token {}", file_path, new_path, identity.name);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "filename": new_filename,
+        "category": category.name,
+        "url": format!("/api/image/{}", new_filename),
+    })))
 }
 
 // Get file list
-#[actix_web::get("/api/list/{subfolder}")]
+#[actix_web::get("/api/list/{category}")]
 pub async fn get_list(
-    subfolder: web::Path<String>,
-    data: web::Data<Vec<Vec<String>>>,
-) -> impl Responder {
-    let subfolder = subfolder.into_inner();
-    let pc_images = &data[0];
-    let mp_images = &data[1];
-    let filtered_images: Vec<&String> = if subfolder == "all" {
-        pc_images.iter().chain(mp_images.iter()).collect()
-    } else if subfolder == "pc" {
-        pc_images.iter().collect()
-    } else if subfolder == "mp" {
-        mp_images.iter().collect()
-    } else {
-        return HttpResponse::NotFound().json(Value::String("Invalid subfolder.".to_string()));
+    category: web::Path<String>,
+    query: web::Query<FilterQuery>,
+    index: web::Data<RwLock<ImageIndex>>,
+) -> Result<HttpResponse, ApiError> {
+    let category = category.into_inner();
+    let filter = match query.filter() {
+        Ok(filter) => filter,
+        Err(e) => return Err(ApiError::BadRequest(e)),
+    };
+    let index = index.read().unwrap();
+    let filtered_images: Vec<&String> = match index.category(&category) {
+        Some(images) => images
+            .into_iter()
+            .filter(|path| filter.matches(&index, path))
+            .collect(),
+        None => {
+            return Err(ApiError::NotFound("Invalid category.".to_string()))
+        }
     };
-        
 
     if filtered_images.is_empty() {
-        return HttpResponse::NotFound().json(Value::String("No images found.".to_string()));
+        return Err(ApiError::NotFound("No images found.".to_string()));
     }
 
     let mut file_list = Vec::new();
     for image in filtered_images {
         // Only return filename
-        let file_name = Path::new(image)
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
-        file_list.push(file_name);
+        file_list.push(file_name(image).to_string());
+    }
+
+    Ok(HttpResponse::Ok().json(file_list))
+}
+
+// Get the configured categories with their image counts
+#[actix_web::get("/api/categories")]
+pub async fn get_categories(
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    let index = index.read().unwrap();
+    let categories: Vec<Value> = config
+        .categories
+        .iter()
+        .map(|category| {
+            serde_json::json!({
+                "name": category.name,
+                "description": category.description,
+                "count": index.count(&category.name),
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(categories))
+}
+
+// Expose the metrics in the Prometheus text format
+#[actix_web::get("/metrics")]
+pub async fn get_metrics(
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    if !config.metrics.enabled {
+        return Err(ApiError::NotFound("Not found.".to_string()));
+    }
+    if let Some(secret_hash) = &config.metrics.secret_hash {
+        if !bearer_matches(&req, secret_hash) {
+            return Err(ApiError::Unauthorized);
+        }
+    }
+    {
+        let index = index.read().unwrap();
+        for category in &config.categories {
+            let count = index.count(&category.name) as i64;
+            METRICS.images.with_label_values(&[&category.name]).set(count);
+        }
+    }
+    match METRICS.encode() {
+        Ok(body) => Ok(HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body)),
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            Err(ApiError::Internal("Failed to encode metrics.".to_string()))
+        }
+    }
+}
+
+// Get the category of an indexed image by its filename
+fn image_category(index: &ImageIndex, filename: &str) -> Option<String> {
+    let path = index.find(filename)?;
+    index.category_of(Path::new(path)).map(str::to_string)
+}
+
+#[derive(Deserialize)]
+pub struct WeightBody {
+    pub weight: f64,
+}
+
+// Set how often an image is picked by the random endpoint relative to the others
+#[actix_web::put("/api/weight/{filename}")]
+pub async fn set_weight(
+    filename: web::Path<String>,
+    body: web::Json<WeightBody>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let identity = authorize(&req, &config, Scope::Edit)?;
+    if !body.weight.is_finite() || body.weight < 0.0 {
+        return Err(ApiError::BadRequest("Weight must be a non-negative number.".to_string()));
+    }
+    let filename = filename.into_inner();
+    let mut index = index.write().unwrap();
+    match image_category(&index, &filename) {
+        Some(category) => identity.check_category(&category)?,
+        None => return Err(ApiError::NotFound("Image not found.".to_string())),
     }
+    index.set_weight(&filename, body.weight);
+    // Persist the weights in the sidecar file so they survive a restart
+    write_weights(&config.image_folder, index.weights())?;
+    info!(
+        "Weight of {} set to {} with token {}",
+        filename, body.weight, identity.name
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "filename": filename,
+        "weight": body.weight,
+    })))
+}
 
-    HttpResponse::Ok().json(file_list)
+// Get the stored metadata of an image
+#[actix_web::get("/api/metadata/{filename}")]
+pub async fn get_metadata(
+    filename: web::Path<String>,
+    config: web::Data<Config>,
+    store: web::Data<MetadataStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    // The metadata includes the uploader's address
+    authorize(&req, &config, Scope::Admin)?;
+    match store.get(&filename) {
+        Ok(Some(metadata)) => Ok(HttpResponse::Ok().json(metadata)),
+        Ok(None) => Err(ApiError::NotFound("Image not found.".to_string())),
+        Err(e) => {
+            error!("Failed to read metadata of {}: {}", filename, e);
+            Err(ApiError::Internal("Failed to read metadata.".to_string()))
+        }
+    }
 }
 
-#[actix_web::post("/api/images/{subfolder}")]
+#[actix_web::post("/api/images/{category}")]
 pub async fn upload_image(
+    payload: Multipart,
+    category: web::Path<String>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    store: web::Data<MetadataStore>,
+    client: ClientIp,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let result = save_uploads(payload, category, index, config, store, client, req).await;
+    if let Err(e) = &result {
+        METRICS.uploads.with_label_values(&[e.code()]).inc();
+    }
+    result
+}
+
+// Save the images of an upload, returns where they were placed
+async fn save_uploads(
     mut payload: Multipart,
-    subfolder: web::Path<String>,
-    data: web::Data<Vec<Vec<String>>>,
+    category: web::Path<String>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    store: web::Data<MetadataStore>,
+    client: ClientIp,
     req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let token = &data[2][1];
-    // Record the ip address of the visitor
-    let ip_str = if let Some(cf_ip) = req.headers().get("CF-Connecting-IP") {
-        cf_ip.to_str().unwrap_or("").to_string() // Convert to String
-    } else if let Some(peer_addr) = req.peer_addr() {
-        peer_addr.ip().to_string()
-    } else {
-        "".to_string() // Could not get the ip address
+) -> Result<HttpResponse, ApiError> {
+    let identity = match authorize(&req, &config, Scope::Upload) {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!(
+                "Unauthorized upload from IP: {}, Country: {}",
+                client,
+                client.country.as_deref().unwrap_or("unknown")
+            );
+            return Err(e);
+        }
     };
-    let country = if let Some(cf_country) = req.headers().get("CF-IPCountry") {
-        cf_country.to_str().unwrap_or("Unknown country").to_string()
-    } else {
-        "Unknown country".to_string()
+    let image_folder = &config.image_folder;
+    // Only upload to configured categories, anything else would never be indexed,
+    // the 'auto' target picks the category of each image from its aspect ratio
+    let target = match config.categories.iter().find(|c| c.name == *category) {
+        Some(category) => Some(category),
+        None if *category == AUTO_CATEGORY => None,
+        None => return Err(ApiError::NotFound("Invalid category.".to_string())),
     };
-    // Check authentication, should be Bearer <token>
-    let auth_header = req.headers().get("Authorization");
-    if auth_header
-        != Some(
-            &actix_web::http::header::HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
-        )
-    {
-        println!(
-            "Unauthorized access from IP: {}, Country: {}",
-            ip_str, country
-        );
-        return Err(actix_web::error::ErrorUnauthorized("Unauthorized."));
+    if let Some(category) = target {
+        identity.check_category(&category.name)?;
     }
-    // Get the folder path from the data
-    let image_folder = &data[2][0];
+    let uploads = &config.uploads;
+    // Turn away requests announcing too much data before reading any of it
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > uploads.max_request_bytes) {
+        return Err(request_too_large(uploads));
+    }
+    let mut request_bytes = 0;
+    let mut files = 0;
     let mut filepaths: Vec<String> = Vec::new();
-    while let Ok(Some(mut field)) = payload.try_next().await {
+    let mut placements: Vec<Value> = Vec::new();
+    let mut uploaded: Vec<String> = Vec::new();
+    let mut tags = BTreeSet::new();
+    while let Some(mut field) = payload.try_next().await? {
         let content_disposition = field.content_disposition();
+        // The tags field applies to every file of the request, wherever it is placed
+        if content_disposition.get_name() == Some("tags")
+            && content_disposition.get_filename().is_none()
+        {
+            let mut buffer = Vec::new();
+            while let Some(chunk) = field.next().await {
+                let data = chunk?;
+                request_bytes += data.len();
+                if request_bytes > uploads.max_request_bytes {
+                    return Err(request_too_large(uploads));
+                }
+                buffer.extend_from_slice(&data);
+            }
+            tags.extend(parse_tags(&String::from_utf8_lossy(&buffer)));
+            continue;
+        }
         let filename = match content_disposition.get_filename() {
             Some(name) => name,
-            None => return Err(actix_web::error::ErrorBadRequest("No filename found.")),
+            None => return Err(ApiError::BadRequest("No filename found.".to_string())),
         };
-        // Get the subfolder from the path as a string
-        let subfolder = subfolder.clone();
-        let folder_path = format!("{}/{}", image_folder, subfolder);
-
-        fs::create_dir_all(&folder_path)?;
-
-        let mut hasher = Md5::new();
-        hasher.update(filename.as_bytes());
-        let hash_result = hasher.finalize();
-        let new_filename = format!("{:x}.webp", hash_result);
-        let new_filepath = format!("{}/{}", folder_path, new_filename);
+        let original_filename = filename.to_string();
+        files += 1;
+        if files > uploads.max_files {
+            return Err(ApiError::PayloadTooLarge(format!(
+                "At most {} files can be uploaded at once.",
+                uploads.max_files
+            )));
+        }
 
         let mut buffer = Vec::new();
-        // Read the data from the field
+        // Read the data from the field, stopping as soon as a limit is exceeded
         while let Some(chunk) = field.next().await {
             let data = chunk?;
+            request_bytes += data.len();
+            if request_bytes > uploads.max_request_bytes {
+                return Err(request_too_large(uploads));
+            }
+            if buffer.len() + data.len() > uploads.max_file_bytes {
+                return Err(ApiError::PayloadTooLarge(format!(
+                    "{} exceeds the maximum of {} bytes per file.",
+                    original_filename, uploads.max_file_bytes
+                )));
+            }
             buffer.extend_from_slice(&data);
         }
 
         // Load the image from the buffer
-        let img = ImageReader::new(std::io::Cursor::new(buffer))
-            .with_guessed_format()
-            .expect("Failed to guess image format")
-            .decode()
-            .expect("Failed to decode image");
+        let img = decode_upload(buffer, &original_filename, uploads)?;
+
+        // Name the file after its pixels, so different images never overwrite each other
+        let new_filename = format!("{}.webp", content_hash(&img));
+        let mut webp = Vec::new();
+        if let Err(e) = img.write_to(&mut Cursor::new(&mut webp), ImageFormat::WebP) {
+            error!("Failed to encode image: {}", e);
+            return Err(ApiError::Internal("Failed to save image.".to_string()));
+        }
+
+        // Hand out the stored copy of an image that was already uploaded
+        if let Some((existing, category)) = find_duplicate(&index, &store, &new_filename, &webp) {
+            let filename = file_name(&existing);
+            info!("Image uploaded from {} is a duplicate of {}", client, existing);
+            filepaths.push(format!("/api/image/{}", filename));
+            placements.push(serde_json::json!({
+                "url": format!("/api/image/{}", filename),
+                "filename": original_filename,
+                "category": category,
+                "duplicate": true,
+            }));
+            METRICS.uploads.with_label_values(&["duplicate"]).inc();
+            continue;
+        }
+
+        // Optionally turn away re-encoded or resized copies of stored images
+        if config.near_duplicates.reject {
+            let hash = dhash(&img);
+            let (index, store, config) = (index.clone(), store.clone(), config.clone());
+            let near_duplicate =
+                web::block(move || find_near_duplicate(&index, &store, hash, &config)).await?;
+            if let Some(existing) = near_duplicate {
+                info!("Image uploaded from {} is a near-duplicate of {}", client, existing);
+                return Err(ApiError::Conflict(format!(
+                    "{} is a near-duplicate of /api/image/{}.",
+                    original_filename, existing
+                )));
+            }
+        }
+
+        let category = match target {
+            Some(category) => category,
+            None => match classify(&config.categories, img.width(), img.height()) {
+                Some(category) => category,
+                None => {
+                    return Err(ApiError::Unprocessable(format!(
+                        "No category accepts the aspect ratio of {}.",
+                        original_filename
+                    )))
+                }
+            },
+        };
+        identity.check_category(&category.name)?;
+        let folder_path = format!("{}/{}", image_folder, category.folder);
+
+        fs::create_dir_all(&folder_path)?;
+
+        let new_filepath = format!("{}/{}", folder_path, new_filename);
 
         // Save the image to the file
-        match img.save_with_format(new_filepath.clone(), ImageFormat::WebP) {
+        match fs::write(&new_filepath, &webp) {
             Ok(_) => {
                 filepaths.push("/api/image/".to_owned()+new_filename.as_str());
+                placements.push(serde_json::json!({
+                    "url": format!("/api/image/{}", new_filename),
+                    "filename": original_filename,
+                    "category": category.name,
+                }));
+                uploaded.push(new_filename.clone());
+                METRICS.uploads.with_label_values(&["success"]).inc();
 
-                println!("Image uploaded from {} saved to {}",ip_str, new_filepath);
-                match create_thumbnail(Path::new(&new_filepath), 200, 200, &image_folder) {
+                info!(
+                    "Image uploaded from {} with token {} saved to {}",
+                    client, identity.name, new_filepath
+                );
+                // Record who uploaded the image and under which name
+                match ImageMetadata::from_file(Path::new(&new_filepath), &category.name) {
+                    Ok(metadata) => {
+                        let metadata = ImageMetadata {
+                            uploaded_at: chrono::Utc::now().timestamp(),
+                            uploader_ip: client.ip.map(|ip| ip.to_string()),
+                            uploader_country: client.country.clone(),
+                            original_filename: Some(original_filename.clone()),
+                            ..metadata
+                        };
+                        if let Err(e) = store.upsert(&metadata) {
+                            error!("Failed to store metadata of {}: {}", new_filename, e);
+                        }
+                    }
+                    Err(e) => error!("Failed to read metadata of {}: {}", new_filepath, e),
+                }
+                match create_thumbnail(Path::new(&new_filepath), 200, 200, image_folder) {
                     Ok(_) => {
-                        println!("Created thumbnail for {new_filepath}");
+                        info!("Created thumbnail for {new_filepath}");
+                        // Make the image available right away instead of waiting for the watcher
+                        index.write().unwrap().insert(Path::new(&new_filepath));
                     }
                     Err(e) => {
-                        eprintln!("Failed to create thumbnail: {e}");
-                        return Err(actix_web::error::ErrorInternalServerError(
-                            "Image uploaded successfully, but failed to create thumbnail.",
+                        error!("Failed to create thumbnail: {e}");
+                        return Err(ApiError::Internal(
+                            "Image uploaded successfully, but failed to create thumbnail."
+                                .to_string(),
                         ));
                     }
                 }
             }
             Err(e) => {
-                eprintln!("Failed to save image: {}", e);
-                return Err(actix_web::error::ErrorInternalServerError(
-                    "Failed to save image.",
-                ));
+                error!("Failed to save image: {}", e);
+                return Err(ApiError::Internal("Failed to save image.".to_string()));
             }
         }
     }
+    if !tags.is_empty() {
+        let mut index = index.write().unwrap();
+        for filename in &uploaded {
+            if let Err(e) = store.set_tags(filename, &tags) {
+                error!("Failed to store tags of {}: {}", filename, e);
+            }
+            index.set_tags(filename, tags.clone());
+        }
+    }
+    // Tell where each file landed when the category was picked for it
+    if target.is_none() {
+        return Ok(HttpResponse::Ok().json(placements));
+    }
     Ok(HttpResponse::Ok().json(filepaths))
 }
 
-#[actix_web::get("/api/images/{subfolder}")]
-pub async fn list_images(
-    subfolder: web::Path<String>,
-    data: web::Data<Vec<Vec<String>>>,
-    req: HttpRequest,
-) -> impl Responder {
-    let pc_images = &data[0];
-    let mp_images = &data[1];
-    // Get the visitor's ip address and print to log
-    let ip_str = if let Some(cf_ip) = req.headers().get("CF-Connecting-IP") {
-        cf_ip.to_str().unwrap_or("").to_string() // Convert to String
-    } else if let Some(peer_addr) = req.peer_addr() {
-        peer_addr.ip().to_string()
-    } else {
-        "".to_string() // Could not get the ip address
+fn request_too_large(uploads: &UploadConfig) -> ApiError {
+    ApiError::PayloadTooLarge(format!(
+        "Uploads are limited to {} bytes per request.",
+        uploads.max_request_bytes
+    ))
+}
+
+// Decode an uploaded image within the configured limits
+fn decode_upload(
+    buffer: Vec<u8>,
+    filename: &str,
+    uploads: &UploadConfig,
+) -> Result<DynamicImage, ApiError> {
+    // The format is sniffed from the content, the extension of the upload is not trusted
+    let format = match image::guess_format(&buffer) {
+        Ok(format) if uploads.allows(format) => format,
+        _ => {
+            return Err(ApiError::UnsupportedMediaType(format!(
+                "{} is not in an accepted format ({}).",
+                filename,
+                uploads.formats.join(", ")
+            )))
+        }
+    };
+    let undecodable = |e: image::ImageError| match e {
+        image::ImageError::Limits(_) => ApiError::PayloadTooLarge(format!(
+            "{} is too large to decode.",
+            filename
+        )),
+        e => ApiError::Unprocessable(format!(
+            "{} could not be decoded: {}",
+            filename, e
+        )),
     };
 
-    // Get the visitor's country and print to log
-    let country = if let Some(cf_country) = req.headers().get("CF-IPCountry") {
-        cf_country.to_str().unwrap_or("Unknown country").to_string()
-    } else {
-        "Unknown country".to_string()
+    // Check the dimensions from the header before allocating the pixels
+    let (width, height) = ImageReader::with_format(Cursor::new(&buffer), format)
+        .into_dimensions()
+        .map_err(undecodable)?;
+    if width > uploads.max_width
+        || height > uploads.max_height
+        || u64::from(width) * u64::from(height) > uploads.max_pixels
+    {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "{} is {}x{}, larger than the maximum of {}x{} or {} pixels.",
+            filename, width, height, uploads.max_width, uploads.max_height, uploads.max_pixels
+        )));
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(uploads.max_width);
+    limits.max_image_height = Some(uploads.max_height);
+    limits.max_alloc = Some(uploads.max_alloc);
+    let mut reader = ImageReader::with_format(Cursor::new(buffer), format);
+    reader.limits(limits);
+    reader.decode().map_err(undecodable)
+}
+
+// Find a stored image identical to an upload, by its content hashed name in any category or
+// by the hash of the encoded file, returns its path and category
+fn find_duplicate(
+    index: &RwLock<ImageIndex>,
+    store: &MetadataStore,
+    filename: &str,
+    webp: &[u8],
+) -> Option<(String, String)> {
+    let index = index.read().unwrap();
+    let existing = match index.find(filename) {
+        Some(path) => path,
+        None => {
+            let hash = format!("{:x}", Sha256::digest(webp));
+            match store.find_by_hash(&hash) {
+                Ok(Some(filename)) => index.find(&filename)?,
+                Ok(None) => return None,
+                Err(e) => {
+                    error!("Failed to look up hash {}: {}", hash, e);
+                    return None;
+                }
+            }
+        }
     };
+    let category = index.category_of(Path::new(existing))?.to_string();
+    Some((existing.clone(), category))
+}
+
+// Find the stored image closest to a perceptual hash within the configured distance
+fn find_near_duplicate(
+    index: &RwLock<ImageIndex>,
+    store: &MetadataStore,
+    hash: u64,
+    config: &Config,
+) -> Option<String> {
+    let phashes = match store.phashes() {
+        Ok(phashes) => phashes,
+        Err(e) => {
+            error!("Failed to read perceptual hashes: {}", e);
+            return None;
+        }
+    };
+    let indexed = index.read().unwrap().categories_by_filename();
+    phashes
+        .into_iter()
+        .filter(|(filename, _)| indexed.contains_key(filename))
+        .map(|(filename, other)| (distance(hash, other), filename))
+        .filter(|(d, _)| *d <= config.near_duplicates.max_distance)
+        .min()
+        .map(|(_, filename)| filename)
+}
+
+#[derive(Deserialize)]
+pub struct DuplicatesQuery {
+    pub distance: Option<u32>,
+}
+
+// Get the view statistics of a range of days
+#[actix_web::get("/api/stats")]
+pub async fn get_stats(
+    query: web::Query<StatsQuery>,
+    config: web::Data<Config>,
+    store: web::Data<MetadataStore>,
+    stats: web::Data<ViewStats>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &config, Scope::Admin)?;
+    if !config.stats.enabled {
+        return Err(ApiError::NotFound("Statistics are disabled.".to_string()));
+    }
+    let (from, to) = query.range().map_err(ApiError::BadRequest)?;
+    let limit = query.limit().map_err(ApiError::BadRequest)?;
+    // Include the views since the last flush
+    stats.flush(&store);
+    let report = store.stats(from, to, limit)?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+// List the groups of near-duplicate images across the whole library
+#[actix_web::get("/api/duplicates")]
+pub async fn get_duplicates(
+    query: web::Query<DuplicatesQuery>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    store: web::Data<MetadataStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req, &config, Scope::Admin)?;
+    let max_distance = query.distance.unwrap_or(config.near_duplicates.max_distance);
+    if max_distance > 64 {
+        return Err(ApiError::BadRequest("Distance must be at most 64.".to_string()));
+    }
+    let phashes = store.phashes().map_err(|e| {
+        error!("Failed to read perceptual hashes: {}", e);
+        ApiError::Internal("Failed to read perceptual hashes.".to_string())
+    })?;
+    let indexed = index.clone();
+    let (clusters, categories) = web::block(move || {
+        let categories = indexed.read().unwrap().categories_by_filename();
+        // Rows of images that were removed from disk are left out
+        let phashes: Vec<(String, u64)> = phashes
+            .into_iter()
+            .filter(|(filename, _)| categories.contains_key(filename))
+            .collect();
+        (clusters(&phashes, max_distance), categories)
+    })
+    .await?;
+    let index = index.read().unwrap();
+    let clusters: Vec<Value> = clusters
+        .iter()
+        .map(|cluster| {
+            let images: Vec<Value> = cluster
+                .iter()
+                .map(|filename| {
+                    let category = categories.get(filename);
+                    let (width, height) = index.dimensions(filename).unwrap_or_default();
+                    serde_json::json!({
+                        "filename": filename,
+                        "url": format!("/api/image/{}", filename),
+                        "category": category,
+                        "width": width,
+                        "height": height,
+                    })
+                })
+                .collect();
+            serde_json::json!({ "images": images })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "max_distance": max_distance,
+        "clusters": clusters,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct ReclassifyQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
 
-    println!(
-        "Visitor IP: {}, Country: {}, Subfolder: {}",
-        ip_str, country, subfolder
+// Move the existing images to the category their aspect ratio belongs to
+#[actix_web::post("/api/reclassify")]
+pub async fn reclassify_images(
+    query: web::Query<ReclassifyQuery>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    store: web::Data<MetadataStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let identity = authorize(&req, &config, Scope::Admin)?;
+    let dry_run = query.dry_run;
+    let moves = web::block(move || reclassify(&config, &index, &store, dry_run)).await?;
+    info!(
+        "Reclassified {} images{} with token {}",
+        moves.len(),
+        if dry_run { " (dry run)" } else { "" },
+        identity.name
     );
+    Ok(HttpResponse::Ok().json(moves))
+}
+
+#[derive(Deserialize)]
+pub struct TagsBody {
+    pub tags: Vec<String>,
+}
+
+// Get the tags of an image
+#[actix_web::get("/api/tags/{filename}")]
+pub async fn get_tags(
+    filename: web::Path<String>,
+    index: web::Data<RwLock<ImageIndex>>,
+) -> Result<HttpResponse, ApiError> {
+    let index = index.read().unwrap();
+    if index.find(&filename).is_none() {
+        return Err(ApiError::NotFound("Image not found.".to_string()));
+    }
+    let tags: Vec<&String> = index.tags(&filename).into_iter().flatten().collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "filename": filename.as_str(),
+        "tags": tags,
+    })))
+}
+
+// Replace the tags of an image
+#[actix_web::put("/api/tags/{filename}")]
+pub async fn set_tags(
+    filename: web::Path<String>,
+    body: web::Json<TagsBody>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    store: web::Data<MetadataStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let identity = authorize(&req, &config, Scope::Edit)?;
+    let filename = filename.into_inner();
+    let tags: BTreeSet<String> = body.tags.iter().flat_map(|tag| parse_tags(tag)).collect();
+    let mut index = index.write().unwrap();
+    match image_category(&index, &filename) {
+        Some(category) => identity.check_category(&category)?,
+        None => return Err(ApiError::NotFound("Image not found.".to_string())),
+    }
+    if let Err(e) = store.set_tags(&filename, &tags) {
+        error!("Failed to store tags of {}: {}", filename, e);
+        return Err(ApiError::Internal("Failed to store tags.".to_string()));
+    }
+    index.set_tags(&filename, tags.clone());
+    info!("Tags of {} set to {:?} with token {}", filename, tags, identity.name);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "filename": filename,
+        "tags": tags,
+    })))
+}
 
-    let subfolder = subfolder.into_inner();
-    let filtered_images: Vec<&String> = if subfolder == "pc" {
-        pc_images.iter().collect()
-    } else if subfolder == "mp" {
-        mp_images.iter().collect()
-    } else if subfolder == "all" {
-        pc_images.iter().chain(mp_images.iter()).collect()
+#[actix_web::route("/api/images/{category}", method = "GET", method = "HEAD")]
+pub async fn list_images(
+    category: web::Path<String>,
+    query: web::Query<RandomQuery>,
+    index: web::Data<RwLock<ImageIndex>>,
+    config: web::Data<Config>,
+    bags: web::Data<ShuffleBags>,
+    stats: web::Data<ViewStats>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let category = category.into_inner();
+    // Identify the client of a shuffle bag, a new client gets a cookie and every client gets
+    // its id in a header
+    let shuffle_cookie = &config.random.shuffle.cookie;
+    let mut new_client = None;
+    let shuffle_client = if query.shuffle || query.client.is_some() {
+        let id = match &query.client {
+            Some(client) => Some(client.clone()),
+            None => req.cookie(shuffle_cookie).map(|cookie| cookie.value().to_string()),
+        };
+        let (client, is_new) = bags.client(id.as_deref());
+        if is_new {
+            new_client = Some(client.clone());
+        }
+        Some(client)
     } else {
-        return HttpResponse::NotFound().json(Value::String("Invalid subfolder.".to_string()));
+        None
+    };
+    let filter = match query.filter() {
+        Ok(filter) => filter,
+        Err(e) => return Err(ApiError::BadRequest(e)),
     };
+    // Release the index before reading from disk so the watcher is not blocked
+    let (random_image, image_category) = {
+        let index = index.read().unwrap();
+        let sampler = match index.sampler(&category) {
+            Some(sampler) => sampler,
+            None => {
+                return Err(ApiError::NotFound("Invalid category.".to_string()))
+            }
+        };
 
-    if filtered_images.is_empty() {
-        return HttpResponse::NotFound().json(Value::String("No images found.".to_string()));
+        if sampler.is_empty() {
+            return Err(ApiError::NotFound("No images found.".to_string()));
+        }
+
+        let random_index = match &shuffle_client {
+            Some(client) => bags.next(client, &category, &filter, &index),
+            None if filter.is_empty() => Some(query.pick(sampler, config.random.timezone)),
+            None => {
+                // Sample the matching images by their weights
+                let candidates: Vec<usize> = (0..index.count(&category))
+                    .filter(|&i| {
+                        index
+                            .nth(&category, i)
+                            .is_some_and(|path| filter.matches(&index, path))
+                    })
+                    .collect();
+                let weights: Vec<f64> = candidates
+                    .iter()
+                    .filter_map(|&i| index.nth(&category, i))
+                    .map(|path| index.weight(file_name(path)))
+                    .collect();
+                let sampler = AliasTable::new(&weights);
+                if sampler.is_empty() {
+                    None
+                } else {
+                    Some(candidates[query.pick(&sampler, config.random.timezone)])
+                }
+            }
+        };
+        match random_index.and_then(|i| index.nth(&category, i)) {
+            Some(image) => {
+                METRICS.random_picks.with_label_values(&[&category]).inc();
+                let image_category = index.category_of(Path::new(image)).unwrap_or_default();
+                (image.clone(), image_category.to_string())
+            }
+            None => {
+                return Err(ApiError::NotFound("No images found.".to_string()))
+            }
+        }
+    };
+    // Record the pick under the category of the image, 'all' spans several
+    let picked = file_name(&random_image).to_string();
+    if config.stats.enabled && req.method() == Method::GET {
+        stats.record_pick(&picked, &image_category);
+    }
+
+    // The query overrides the mode of the category, which overrides the default
+    let mode = query
+        .mode
+        .or_else(|| {
+            let category = config.categories.iter().find(|c| c.name == category);
+            category.and_then(|c| c.mode)
+        })
+        .unwrap_or(config.random.mode);
+    let mut response = HttpResponse::Ok();
+    response.insert_header((header::CACHE_CONTROL, config.cache_control.random.as_str()));
+    // Clients without cookies pass the id back in the client parameter
+    if let Some(client) = &shuffle_client {
+        response.insert_header((SHUFFLE_CLIENT_HEADER, client.as_str()));
+    }
+    if let Some(client) = new_client {
+        response.cookie(
+            Cookie::build(shuffle_cookie.clone(), client)
+                .path("/")
+                .http_only(true)
+                .max_age(CookieDuration::days(365))
+                .finish(),
+        );
     }
 
-    let mut rng = rand::thread_rng();
-    let random_index = rng.gen_range(0..filtered_images.len());
-    let random_image = filtered_images.get(random_index).unwrap();
+    if mode == RandomMode::Redirect {
+        let status = match config.random.redirect_status {
+            307 => StatusCode::TEMPORARY_REDIRECT,
+            // Any other value is rejected when the config is loaded
+            _ => StatusCode::FOUND,
+        };
+        return Ok(response
+            .status(status)
+            .insert_header((header::LOCATION, format!("/api/image/{}", file_name(&random_image))))
+            .finish());
+    }
+
+    let format = OutputFormat::negotiate(&req);
+    let cache_folder = config.renditions.cache_folder.clone();
+    let random_image =
+        match image_file(random_image, ImageQuery::default(), format, cache_folder).await {
+            Ok(path) => path,
+            // The file may have vanished since it was picked
+            Err(e) if is_not_found(&e) => {
+                return Err(ApiError::NotFound("Image not found.".to_string()))
+            }
+            Err(e) => {
+                error!("Failed to transcode image: {}", e);
+                return Err(ApiError::Internal("Failed to transcode image.".to_string()));
+            }
+        };
 
-    let mut file = File::open(random_image).unwrap();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
+    // The file may have vanished since it was picked
+    let buffer = match fs::read(&random_image) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            error!("Failed to read {:?}: {}", random_image, e);
+            return Err(ApiError::NotFound("Image not found.".to_string()));
+        }
+    };
+    if config.stats.enabled && req.method() == Method::GET {
+        stats.record_view(&picked, &image_category, ClientIp::of(&req).country.as_deref());
+    }
 
-    HttpResponse::Ok().content_type("image/jpeg").body(buffer)
+    Ok(response
+        .content_type(format.mime())
+        .insert_header((header::VARY, "Accept"))
+        .body(buffer))
 }
 
 // Get the specified thumbnail
-#[actix_web::get("/api/thumbnail/{filename}")]
+#[actix_web::route("/api/thumbnail/{filename}", method = "GET", method = "HEAD")]
 pub async fn get_thumbnail(
     filename: web::Path<String>,
-    data: web::Data<Vec<Vec<String>>>,
-) -> impl Responder {
+    config: web::Data<Config>,
+    etags: web::Data<EtagCache>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
     let filename = filename.into_inner();
-    let img_folder = &data[2][0];
-    let mut file = File::open(format!("{}/thumbnails/{}", img_folder, filename)).unwrap();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-    HttpResponse::Ok().content_type("image/jpeg").body(buffer)
+    let img_folder = &config.image_folder;
+    let mut thumbnail_path = PathBuf::from(format!("{}/thumbnails/{}", img_folder, filename));
+    // Transcode the thumbnail for clients that do not accept WebP
+    let format = OutputFormat::negotiate(&req);
+    if format != OutputFormat::WebP {
+        let cache_folder = config.renditions.cache_folder.clone();
+        let transcoded =
+            web::block(move || transcode_thumbnail(&thumbnail_path, format, &cache_folder)).await;
+        thumbnail_path = match transcoded {
+            Ok(Ok(path)) => path,
+            Ok(Err(e)) if is_not_found(&e) => {
+                return Err(ApiError::NotFound("Thumbnail not found.".to_string()))
+            }
+            Ok(Err(e)) => {
+                error!("Failed to transcode thumbnail {}: {}", filename, e);
+                return Err(ApiError::Internal("Failed to transcode thumbnail.".to_string()));
+            }
+            Err(e) => {
+                error!("Failed to transcode thumbnail {}: {}", filename, e);
+                return Err(ApiError::Internal("Failed to transcode thumbnail.".to_string()));
+            }
+        };
+    }
+    let cache_control = &config.cache_control.thumbnail;
+    match serve_file(&req, &thumbnail_path, format.mime(), cache_control, &etags) {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            error!("Failed to read {:?}: {}", thumbnail_path, e);
+            Err(ApiError::NotFound("Thumbnail not found.".to_string()))
+        }
+    }
 }
\ No newline at end of file
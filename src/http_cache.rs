@@ -0,0 +1,134 @@
+use actix_web::http::header::{
+    self, ByteRangeSpec, EntityTag, Header, HttpDate, IfModifiedSince, IfNoneMatch, IfRange,
+    Range,
+};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::metrics::METRICS;
+
+// Content hashes of the served files, recomputed only when a file changes
+#[derive(Default)]
+pub struct EtagCache {
+    etags: Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>,
+}
+
+impl EtagCache {
+    // Get the hash of a file, the modification time and size tell if the cached one is stale
+    fn etag(&self, path: &Path, modified: SystemTime, len: u64) -> std::io::Result<String> {
+        if let Some((cached_modified, cached_len, etag)) = self.etags.lock().unwrap().get(path) {
+            if *cached_modified == modified && *cached_len == len {
+                METRICS.cache_lookup("etag", true);
+                return Ok(etag.clone());
+            }
+        }
+        METRICS.cache_lookup("etag", false);
+        // Hash without holding the lock, a concurrent request may hash the same file
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+        let etag = format!("{:x}", Md5::digest(&buffer));
+        self.etags
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (modified, len, etag.clone()));
+        Ok(etag)
+    }
+}
+
+// Serve a file with validators, answering conditional and range requests
+pub fn serve_file(
+    req: &HttpRequest,
+    path: &Path,
+    content_type: &str,
+    cache_control: &str,
+    etags: &EtagCache,
+) -> std::io::Result<HttpResponse> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?;
+    let len = metadata.len();
+    let etag = EntityTag::new_strong(etags.etag(path, modified, len)?);
+    let last_modified = HttpDate::from(modified);
+
+    let mut res = HttpResponse::build(StatusCode::OK);
+    res.insert_header(header::ETag(etag.clone()))
+        .insert_header(header::LastModified(last_modified))
+        .insert_header((header::CACHE_CONTROL, cache_control))
+        // Every image endpoint negotiates the format from the Accept header
+        .insert_header((header::VARY, "Accept"));
+
+    // If-None-Match takes precedence over If-Modified-Since
+    let not_modified = if req.headers().contains_key(header::IF_NONE_MATCH) {
+        match IfNoneMatch::parse(req) {
+            Ok(IfNoneMatch::Any) => true,
+            Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+            Err(_) => false,
+        }
+    } else {
+        match IfModifiedSince::parse(req) {
+            Ok(IfModifiedSince(since)) => unix_secs(modified) <= unix_secs(since.into()),
+            Err(_) => false,
+        }
+    };
+    if not_modified {
+        return Ok(res.status(StatusCode::NOT_MODIFIED).finish());
+    }
+
+    res.insert_header((header::ACCEPT_RANGES, "bytes"))
+        .content_type(content_type);
+
+    // Only a single byte range is supported, anything else gets the whole file
+    let range = match Range::parse(req) {
+        Ok(Range::Bytes(specs)) if specs.len() == 1 && if_range_matches(req, &etag, modified) => {
+            Some(specs[0].clone())
+        }
+        _ => None,
+    };
+    let mut file = File::open(path)?;
+    match range.map(|spec: ByteRangeSpec| spec.to_satisfiable_range(len)) {
+        Some(Some((start, end))) => {
+            let mut buffer = Vec::new();
+            file.seek(SeekFrom::Start(start))?;
+            file.take(end - start + 1).read_to_end(&mut buffer)?;
+            Ok(res
+                .status(StatusCode::PARTIAL_CONTENT)
+                .insert_header((
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, len),
+                ))
+                .body(buffer))
+        }
+        Some(None) => Ok(res
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", len)))
+            .finish()),
+        None => {
+            let mut buffer = Vec::new();
+            file.read_to_end(&mut buffer)?;
+            Ok(res.body(buffer))
+        }
+    }
+}
+
+// Check that the range still applies to the current file, true without If-Range
+fn if_range_matches(req: &HttpRequest, etag: &EntityTag, modified: SystemTime) -> bool {
+    if !req.headers().contains_key(header::IF_RANGE) {
+        return true;
+    }
+    match IfRange::parse(req) {
+        Ok(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Ok(IfRange::Date(date)) => unix_secs(modified) == unix_secs(date.into()),
+        Err(_) => false,
+    }
+}
+
+// HTTP dates have a resolution of one second
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,312 @@
+use log::warn;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::random::AliasTable;
+use crate::utils::{read_weights, Category};
+
+// In-memory index of the served images, shared by all workers behind a RwLock
+#[derive(Debug, Default, Clone)]
+pub struct ImageIndex {
+    // Category names and their folders, in the configured order
+    folders: Vec<(String, PathBuf)>,
+    images: HashMap<String, Vec<String>>,
+    // Weights of the images by filename, images without one weigh 1
+    weights: HashMap<String, f64>,
+    // Weighted samplers of each category and 'all', rebuilt whenever the index changes
+    samplers: HashMap<String, AliasTable>,
+    // Incremented whenever the index changes, positions from an older generation are stale
+    generation: u64,
+    // Tags of the images by filename
+    tags: HashMap<String, BTreeSet<String>>,
+    // Width and height of the images by filename, read when they are indexed
+    dimensions: HashMap<String, (u32, u32)>,
+}
+
+impl ImageIndex {
+    pub fn new(image_folder: &str, categories: &[Category]) -> Self {
+        let mut index = ImageIndex {
+            folders: categories
+                .iter()
+                .map(|c| (c.name.clone(), Path::new(image_folder).join(&c.folder)))
+                .collect(),
+            images: categories
+                .iter()
+                .map(|c| (c.name.clone(), Vec::new()))
+                .collect(),
+            weights: HashMap::new(),
+            samplers: HashMap::new(),
+            generation: 0,
+            tags: HashMap::new(),
+            dimensions: HashMap::new(),
+        };
+        index.rebuild_samplers();
+        index
+    }
+
+    // Get the weighted sampler of a category, 'all' samples every category
+    pub fn sampler(&self, name: &str) -> Option<&AliasTable> {
+        self.samplers.get(name)
+    }
+
+    // Get the nth image of a category in the order of its sampler
+    pub fn nth(&self, name: &str, n: usize) -> Option<&String> {
+        if name != "all" {
+            return self.images.get(name)?.get(n);
+        }
+        let mut n = n;
+        for (name, _) in &self.folders {
+            let images = &self.images[name];
+            if n < images.len() {
+                return images.get(n);
+            }
+            n -= images.len();
+        }
+        None
+    }
+
+    // Get the weight of an image by its filename
+    pub fn weight(&self, filename: &str) -> f64 {
+        self.weights.get(filename).copied().unwrap_or(1.0)
+    }
+
+    // Get the weights that differ from the default
+    pub fn weights(&self) -> &HashMap<String, f64> {
+        &self.weights
+    }
+
+    // Set the weight of an image by its filename
+    pub fn set_weight(&mut self, filename: &str, weight: f64) {
+        if weight == 1.0 {
+            self.weights.remove(filename);
+        } else {
+            self.weights.insert(filename.to_string(), weight);
+        }
+        self.rebuild_samplers();
+    }
+
+    // Replace all the weights, e.g. when the sidecar file is imported
+    pub fn set_weights(&mut self, weights: HashMap<String, f64>) {
+        self.weights = weights;
+        self.rebuild_samplers();
+    }
+
+    // Get the tags of an image by its filename
+    pub fn tags(&self, filename: &str) -> Option<&BTreeSet<String>> {
+        self.tags.get(filename)
+    }
+
+    // Replace the tags of an image by its filename
+    pub fn set_tags(&mut self, filename: &str, tags: BTreeSet<String>) {
+        if tags.is_empty() {
+            self.tags.remove(filename);
+        } else {
+            self.tags.insert(filename.to_string(), tags);
+        }
+    }
+
+    // Replace the tags of every image, e.g. when they are loaded from the metadata store
+    pub fn set_all_tags(&mut self, tags: HashMap<String, BTreeSet<String>>) {
+        self.tags = tags;
+    }
+
+    // Get the width and height of an image by its filename
+    pub fn dimensions(&self, filename: &str) -> Option<(u32, u32)> {
+        self.dimensions.get(filename).copied()
+    }
+
+    fn rebuild_samplers(&mut self) {
+        let mut samplers = HashMap::new();
+        for (name, images) in &self.images {
+            let weights: Vec<f64> = images.iter().map(|p| self.weight(file_name(p))).collect();
+            samplers.insert(name.clone(), AliasTable::new(&weights));
+        }
+        let all: Vec<f64> = self
+            .folders
+            .iter()
+            .flat_map(|(name, _)| &self.images[name])
+            .map(|p| self.weight(file_name(p)))
+            .collect();
+        samplers.insert("all".to_string(), AliasTable::new(&all));
+        self.samplers = samplers;
+        self.generation += 1;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Get the images of a category, 'all' chains every category
+    pub fn category(&self, name: &str) -> Option<Vec<&String>> {
+        if name == "all" {
+            return Some(
+                self.folders
+                    .iter()
+                    .flat_map(|(name, _)| &self.images[name])
+                    .collect(),
+            );
+        }
+        self.images.get(name).map(|images| images.iter().collect())
+    }
+
+    // Iterate over every image with its category
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &String)> {
+        self.images
+            .iter()
+            .flat_map(|(category, images)| images.iter().map(move |p| (category.as_str(), p)))
+    }
+
+    // Get the category of every image by its filename, for lookups of many images at once
+    pub fn categories_by_filename(&self) -> HashMap<String, String> {
+        self.entries()
+            .map(|(category, path)| (file_name(path).to_string(), category.to_string()))
+            .collect()
+    }
+
+    // Get the number of images in a category, 'all' counts every category
+    pub fn count(&self, name: &str) -> usize {
+        if name == "all" {
+            return self.len();
+        }
+        self.images.get(name).map_or(0, |images| images.len())
+    }
+
+    // Get the folder of a category
+    pub fn folder(&self, name: &str) -> Option<&Path> {
+        self.folders
+            .iter()
+            .find(|(category, _)| category == name)
+            .map(|(_, folder)| folder.as_path())
+    }
+
+    // Get the category an image belongs to from the folder it is stored in
+    pub fn category_of(&self, path: &Path) -> Option<&str> {
+        if path.extension()? != "webp" {
+            return None;
+        }
+        let parent = path.parent()?;
+        self.folders
+            .iter()
+            .find(|(_, folder)| folder == parent)
+            .map(|(name, _)| name.as_str())
+    }
+
+    // Find the full path of an image by its filename
+    pub fn find(&self, filename: &str) -> Option<&String> {
+        self.images
+            .values()
+            .flatten()
+            .find(|path| file_name(path) == filename)
+    }
+
+    // Add an image to the index, returns false if it was already indexed or is not servable
+    pub fn insert(&mut self, path: &Path) -> bool {
+        let inserted = self.add(path);
+        if inserted {
+            self.rebuild_samplers();
+        }
+        inserted
+    }
+
+    // Add an image without rebuilding the samplers, for bulk indexing
+    fn add(&mut self, path: &Path) -> bool {
+        let category = match self.category_of(path) {
+            Some(category) => category.to_string(),
+            None => return false,
+        };
+        // Read the dimensions again, a replaced file may have other ones
+        let filename = file_name(&path.to_string_lossy()).to_string();
+        match image::image_dimensions(path) {
+            Ok(dimensions) => self.dimensions.insert(filename, dimensions),
+            Err(e) => {
+                warn!("Failed to read dimensions of {:?}: {}", path, e);
+                self.dimensions.remove(&filename)
+            }
+        };
+        let images = self.images.entry(category).or_default();
+        let path = path.to_string_lossy().to_string();
+        // Keep the images sorted so seeded picks only depend on the library content
+        match images.binary_search(&path) {
+            Ok(_) => false,
+            Err(position) => {
+                images.insert(position, path);
+                true
+            }
+        }
+    }
+
+    // Move images to the folders of other categories, the samplers are rebuilt once for all
+    // of them, moves the index already knows about are skipped
+    pub fn move_images(&mut self, moves: &[(String, PathBuf)]) {
+        for (from, to) in moves {
+            let category = match self.category_of(to) {
+                Some(category) => category.to_string(),
+                None => continue,
+            };
+            for images in self.images.values_mut() {
+                if let Ok(position) = images.binary_search(from) {
+                    images.remove(position);
+                }
+            }
+            // The filename stays the same, so do its dimensions, weight and tags
+            let images = self.images.entry(category).or_default();
+            let to = to.to_string_lossy().to_string();
+            if let Err(position) = images.binary_search(&to) {
+                images.insert(position, to);
+            }
+        }
+        if !moves.is_empty() {
+            self.rebuild_samplers();
+        }
+    }
+
+    // Remove an image from the index, returns false if it was not indexed
+    pub fn remove(&mut self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        let before = self.len();
+        for images in self.images.values_mut() {
+            images.retain(|p| *p != path);
+        }
+        if before == self.len() {
+            return false;
+        }
+        self.dimensions.remove(file_name(&path));
+        self.rebuild_samplers();
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.values().map(|images| images.len()).sum()
+    }
+}
+
+// Get the filename of an indexed path
+pub fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("")
+}
+
+pub fn index_images(folder: &str, categories: &[Category]) -> ImageIndex {
+    // Create the index of the image paths excluding the thumbnails
+    let mut images = ImageIndex::new(folder, categories);
+    // Iterate through the folder of each category
+    for category in categories {
+        let category_folder = Path::new(folder).join(&category.folder);
+        for entry in WalkDir::new(category_folder)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            // Check if the path is a file
+            if entry.path().is_file() {
+                images.add(entry.path());
+            }
+        }
+    }
+    // Importing the weights builds the samplers once for the whole library
+    images.set_weights(read_weights(folder));
+    images
+}
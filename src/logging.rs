@@ -0,0 +1,286 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::Error;
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::client_ip::ClientIp;
+use crate::request_id;
+use crate::utils::LoggingConfig;
+
+// Target of the access log records, written as they are without a prefix
+const ACCESS_TARGET: &str = "access";
+
+// Format of the access log lines
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Off,
+    // Apache combined log format followed by the country, latency and request id
+    Combined,
+    // One JSON object per line
+    Json,
+}
+
+// A log file renamed to .1, .2, ... once it grows past the size limit
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // The oldest file is dropped, the others shift by one
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    // Written to stdout and stderr without a file
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = if record.target() == ACCESS_TARGET {
+            record.args().to_string()
+        } else {
+            let request_id = request_id::current();
+            format!(
+                "{} {:<5} [{}] {}",
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                record.level(),
+                if request_id.is_empty() { "-" } else { &request_id },
+                record.args()
+            )
+        };
+        match &self.file {
+            Some(file) => {
+                if let Err(e) = file.lock().unwrap().write_line(&line) {
+                    eprintln!("Failed to write log: {}", e);
+                }
+            }
+            None if record.level() <= Level::Warn => eprintln!("{}", line),
+            None => println!("{}", line),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().file.flush();
+        }
+    }
+}
+
+// Install the logger, RUST_LOG overrides the configured level
+pub fn init(config: &LoggingConfig) -> Result<(), String> {
+    let level = std::env::var("RUST_LOG").unwrap_or_else(|_| config.level.clone());
+    let level =
+        LevelFilter::from_str(&level).map_err(|_| format!("Invalid log level '{}'.", level))?;
+    let file = match &config.file {
+        Some(path) => Some(Mutex::new(
+            RotatingFile::open(path, config.max_file_bytes, config.max_files)
+                .map_err(|e| format!("Failed to open log file {}: {}", path, e))?,
+        )),
+        None => None,
+    };
+    log::set_boxed_logger(Box::new(Logger { level, file })).map_err(|e| e.to_string())?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+// Middleware writing a line to the access log for every request
+pub fn access_log<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+    format: AccessLogFormat,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>> + 'static
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    let start = Instant::now();
+    let entry = (format != AccessLogFormat::Off).then(|| AccessEntry::new(&req));
+    let fut = srv.call(req);
+    async move {
+        let res = fut.await;
+        if let Some(entry) = entry {
+            let (status, bytes) = match &res {
+                Ok(res) => (
+                    res.status().as_u16(),
+                    match res.response().body().size() {
+                        BodySize::Sized(bytes) => bytes,
+                        _ => 0,
+                    },
+                ),
+                Err(e) => (e.as_response_error().status_code().as_u16(), 0),
+            };
+            entry.write(format, status, bytes, start.elapsed().as_secs_f64() * 1000.0);
+        }
+        res
+    }
+}
+
+// Escape a quoted field of the combined format like Apache does, so that a client cannot
+// close the quotes and forge the fields after it
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// What the access log records about a request
+struct AccessEntry {
+    ip: String,
+    country: Option<String>,
+    method: String,
+    path: String,
+    protocol: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl AccessEntry {
+    fn new(req: &ServiceRequest) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let client = ClientIp::of(req.request());
+        AccessEntry {
+            ip: client.to_string(),
+            country: client.country,
+            method: req.method().to_string(),
+            path: req.uri().to_string(),
+            protocol: format!("{:?}", req.version()),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+        }
+    }
+
+    fn write(&self, format: AccessLogFormat, status: u16, bytes: u64, latency_ms: f64) {
+        let now = Utc::now();
+        let request_id = request_id::current();
+        let line = match format {
+            AccessLogFormat::Off => return,
+            AccessLogFormat::Combined => format!(
+                "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {} {:.1}ms {}",
+                self.ip,
+                now.format("%d/%b/%Y:%H:%M:%S %z"),
+                self.method,
+                escape(&self.path),
+                self.protocol,
+                status,
+                bytes,
+                escape(self.referer.as_deref().unwrap_or("-")),
+                escape(self.user_agent.as_deref().unwrap_or("-")),
+                self.country.as_deref().unwrap_or("-"),
+                latency_ms,
+                request_id
+            ),
+            AccessLogFormat::Json => serde_json::json!({
+                "time": now.to_rfc3339_opts(SecondsFormat::Millis, true),
+                "request_id": request_id,
+                "ip": self.ip,
+                "country": self.country,
+                "method": self.method,
+                "path": self.path,
+                "protocol": self.protocol,
+                "status": status,
+                "bytes": bytes,
+                "latency_ms": (latency_ms * 10.0).round() / 10.0,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+            })
+            .to_string(),
+        };
+        log::info!(target: ACCESS_TARGET, "{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_fields_are_escaped() {
+        assert_eq!(escape("curl/7.88.1"), "curl/7.88.1");
+        assert_eq!(escape("a\" 200 \"b"), "a\\\" 200 \\\"b");
+        assert_eq!(escape("c:\\x"), "c:\\\\x");
+        assert_eq!(escape("a\nb\t"), "a\\x0ab\\x09");
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+struct Entry<V> {
+    value: V,
+    // Position in the order of use, higher is more recent
+    tick: u64,
+    weight: usize,
+}
+
+// Map dropping its least recently used entries once the total weight of the entries goes
+// over a maximum, every operation is O(log n)
+pub struct LruMap<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    weight: usize,
+    max_weight: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruMap<K, V> {
+    pub fn new(max_weight: usize) -> Self {
+        LruMap {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            weight: 0,
+            max_weight,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Get an entry and mark it as the most recently used
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(&mut entry.value)
+    }
+
+    // Insert or replace an entry as the most recently used one
+    pub fn insert(&mut self, key: K, value: V, weight: usize) {
+        self.remove(&key);
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key.clone(),
+            Entry {
+                value,
+                tick: self.tick,
+                weight,
+            },
+        );
+        self.weight += weight;
+        self.evict(&key);
+    }
+
+    // Change the weight of an entry, e.g. after it grew
+    pub fn set_weight(&mut self, key: &K, weight: usize) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.weight = self.weight - entry.weight + weight;
+            entry.weight = weight;
+            self.evict(key);
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.weight -= entry.weight;
+        Some(entry.value)
+    }
+
+    // Drop the least recently used entries as long as they are stale
+    pub fn remove_stale(&mut self, mut stale: impl FnMut(&mut V) -> bool) {
+        while let Some((_, key)) = self.order.first_key_value() {
+            let key = key.clone();
+            let is_stale = match self.entries.get_mut(&key) {
+                Some(entry) => stale(&mut entry.value),
+                None => false,
+            };
+            if !is_stale {
+                break;
+            }
+            self.remove(&key);
+        }
+    }
+
+    // Drop the least recently used entries until the weight fits, except the one being kept
+    fn evict(&mut self, keep: &K) {
+        while self.weight > self.max_weight {
+            let oldest = self.order.values().find(|key| *key != keep).cloned();
+            match oldest {
+                Some(key) => {
+                    self.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_entries_are_dropped_first() {
+        let mut lru = LruMap::new(2);
+        lru.insert("a", 1, 1);
+        lru.insert("b", 2, 1);
+        assert_eq!(lru.get_mut(&"a"), Some(&mut 1));
+        lru.insert("c", 3, 1);
+        assert_eq!(lru.len(), 2);
+        assert!(lru.get_mut(&"b").is_none());
+        assert!(lru.get_mut(&"a").is_some());
+    }
+
+    #[test]
+    fn weight_bounds_the_entries() {
+        let mut lru = LruMap::new(10);
+        lru.insert("a", (), 4);
+        lru.insert("b", (), 4);
+        lru.set_weight(&"b", 7);
+        assert!(lru.get_mut(&"a").is_none());
+        // An entry heavier than the maximum is kept alone
+        lru.insert("c", (), 20);
+        assert_eq!(lru.len(), 1);
+        assert!(lru.get_mut(&"c").is_some());
+    }
+
+    #[test]
+    fn stale_entries_are_removed_from_the_oldest() {
+        let mut lru = LruMap::new(10);
+        lru.insert("a", 1, 1);
+        lru.insert("b", 5, 1);
+        lru.insert("c", 1, 1);
+        lru.remove_stale(|v| *v < 3);
+        // Stops at the first fresh entry
+        assert_eq!(lru.len(), 2);
+        assert!(lru.get_mut(&"c").is_some());
+    }
+}
@@ -1,34 +1,83 @@
-use actix_web::{web, App, HttpServer};
-use base64::prelude::*;
+use actix_web::{web, App, HttpResponse, HttpServer};
 
+mod auth;
+mod classify;
+mod client_ip;
+mod error;
+mod filter;
 mod handler;
+mod http_cache;
+mod index;
+mod logging;
+mod lru;
+mod metadata;
+mod metrics;
+mod phash;
+mod random;
+mod rate_limit;
+mod request_id;
+mod rendition;
+mod stats;
 mod utils;
+mod watcher;
 
+use classify::reclassify;
+use error::{bad_request, ApiError};
 use handler::*;
+use http_cache::EtagCache;
+use index::*;
+use log::{debug, error, info, warn};
+use metadata::MetadataStore;
+use random::ShuffleBags;
+use rate_limit::RateLimiter;
+use stats::ViewStats;
+use std::sync::RwLock;
+use std::time::Duration;
 use utils::*;
+use watcher::*;
 
 
 #[actix_web::main] // <- Start actix-web
 async fn main() -> std::io::Result<()> {
-    let config = read_config("config.json");
+    // `hash-token <secret>` prints the hash to put in the secret_hash of a token
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("hash-token") {
+        match args.get(2) {
+            Some(secret) => println!("{}", auth::hash_secret(secret)),
+            None => eprintln!("Usage: hash-token <secret>"),
+        }
+        return Ok(());
+    }
+
+    let config = match read_config("config.json") {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to read config.json: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = logging::init(&config.logging) {
+        eprintln!("Failed to set up logging: {}", e);
+        std::process::exit(1);
+    }
 
     // Print the config
-    println!("Config: {:?}", config);
+    debug!("Config: {:?}", config);
 
     // Validate the image folder
-    match validate_folder(&config.image_folder) {
-        Ok(_) => println!("Image folder validated."),
+    match validate_folder(&config.image_folder, &config.categories) {
+        Ok(_) => info!("Image folder validated."),
         Err(e) => {
-            eprintln!("Failed to validate image folder: {}", e);
+            error!("Failed to validate image folder: {}", e);
             // ask the user if they want to create the folder, wait for 3 seconds, default to no
             let mut input = String::new();
             println!("Do you want to create the folder? (y/n)");
             std::io::stdin().read_line(&mut input).unwrap();
             if input.trim() == "y" {
-                match create_folder_structure(&config.image_folder) {
-                    Ok(_) => println!("Folder created."),
+                match create_folder_structure(&config.image_folder, &config.categories) {
+                    Ok(_) => info!("Folder created."),
                     Err(e) => {
-                        eprintln!("Failed to create folder: {}", e);
+                        error!("Failed to create folder: {}", e);
                         return Err(std::io::Error::new(
                             std::io::ErrorKind::NotFound,
                             "Failed to create image folder.",
@@ -44,53 +93,156 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
+    // Open the metadata store, migrating it to the current schema
+    let store = match MetadataStore::open(&config.metadata_db) {
+        Ok(store) => web::Data::new(store),
+        Err(e) => {
+            error!("Failed to open metadata store {}: {}", config.metadata_db, e);
+            std::process::exit(1);
+        }
+    };
+
     // Convert the images to webp format
-    match convert_images_to_webp(&config.image_folder) {
-        Ok(count) => println!("{} images converted to webp.", count),
-        Err(e) => eprintln!("Failed to convert images: {}", e),
-    }
+    let converted = match convert_images_to_webp(&config.image_folder) {
+        Ok(converted) => {
+            info!("{} images converted to webp.", converted.len());
+            converted
+        }
+        Err(e) => {
+            error!("Failed to convert images: {}", e);
+            Vec::new()
+        }
+    };
 
     // Create thumbnails
     match create_thumbnails(&config.image_folder, 200, 200, &config.image_folder) {
-        Ok(count) => println!("{} thumbnails created.", count),
-        Err(e) => eprintln!("Failed to create thumbnails: {}", e),
+        Ok(count) => info!("{} thumbnails created.", count),
+        Err(e) => error!("Failed to create thumbnails: {}", e),
     }
 
-    let images = index_images(&config.image_folder);
+    let mut images = index_images(&config.image_folder, &config.categories);
 
     // Print the number of images indexed
-    println!("{} images indexed. PC: {}, MP: {}", images[0].len() + images[1].len(), images[0].len(), images[1].len());
+    let counts: Vec<String> = config
+        .categories
+        .iter()
+        .map(|c| format!("{}: {}", c.name, images.count(&c.name)))
+        .collect();
+    info!("{} images indexed. {}", images.len(), counts.join(", "));
+
+    // Backfill the metadata of images that are not in the store yet
+    match store.backfill(&images) {
+        Ok((added, removed)) => info!("Metadata backfilled: {} added, {} removed.", added, removed),
+        Err(e) => error!("Failed to backfill metadata: {}", e),
+    }
+    for (path, original) in converted {
+        if let Err(e) = store.set_original_filename(file_name(&path.to_string_lossy()), &original) {
+            error!("Failed to store original filename of {:?}: {}", path, e);
+        }
+    }
+
+    // Load the tags into the index for filtering
+    match store.all_tags() {
+        Ok(tags) => images.set_all_tags(tags),
+        Err(e) => error!("Failed to load tags: {}", e),
+    }
+
+    // The index is shared by all workers and kept up to date by the watcher
+    let index = web::Data::new(RwLock::new(images));
+
+    // `reclassify [--dry-run]` sorts the library into categories by aspect ratio and exits
+    if args.get(1).map(String::as_str) == Some("reclassify") {
+        let dry_run = args.iter().any(|arg| arg == "--dry-run");
+        for m in reclassify(&config, &index, &store, dry_run) {
+            println!("{}: {} -> {}", m.filename, m.from, m.to);
+        }
+        return Ok(());
+    }
+    match watch_images(&config, index.clone(), store.clone()) {
+        Ok(_) => info!("Watching {} for changes.", config.image_folder),
+        Err(e) => error!("Failed to watch image folder: {}", e),
+    }
 
-    let image_folder = config.image_folder.clone();
-    let token = BASE64_STANDARD.encode(config.pwd.as_bytes());
-    let config_vec = vec![image_folder, token];
+    let app_config = web::Data::new(config.clone());
+    let etags = web::Data::new(EtagCache::default());
+    let bags = web::Data::new(ShuffleBags::new(&config.random.shuffle));
+    let access_log = config.logging.access_log;
+    if config.rate_limits.enabled && config.client_ip.header.is_none() {
+        warn!("Rate limits are keyed by the peer address, set client_ip.header behind a proxy.");
+    }
+    let limiter = web::Data::new(RateLimiter::new(&config.rate_limits));
+
+    // Write the view statistics to the store every few seconds
+    let stats = web::Data::new(ViewStats::default());
+    if config.stats.enabled {
+        let stats = stats.clone();
+        let store = store.clone();
+        let period = Duration::from_secs(config.stats.flush_secs.max(1));
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(period);
+            loop {
+                interval.tick().await;
+                stats.flush(&store);
+            }
+        });
+    }
 
-    let mut data_vec = images.clone();
-    data_vec.push(config_vec);
-    
-    
+    let (final_stats, final_store) = (stats.clone(), store.clone());
 
     // Attempt to bind the server to the provided address
     let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(data_vec.clone()))
+            .wrap_fn({
+                let limiter = limiter.clone();
+                move |req, srv| rate_limit::limit(req, srv, limiter.clone())
+            })
+            .wrap_fn(metrics::track)
+            .wrap_fn(move |req, srv| logging::access_log(req, srv, access_log))
+            .wrap_fn(request_id::assign)
+            // Malformed queries, paths and bodies get the same JSON errors as the handlers
+            .app_data(web::QueryConfig::default().error_handler(|e, _| bad_request(e)))
+            .app_data(web::PathConfig::default().error_handler(|e, _| bad_request(e)))
+            .app_data(web::JsonConfig::default().error_handler(|e, _| bad_request(e)))
+            .app_data(index.clone())
+            .app_data(app_config.clone())
+            .app_data(etags.clone())
+            .app_data(bags.clone())
+            .app_data(store.clone())
+            .app_data(stats.clone())
             .route("/", web::get().to(|| async { "Hello, world!" }))
             .service(list_images)
             .service(upload_image)
             .service(get_thumbnail)
             .service(get_list)
+            .service(get_categories)
+            .service(get_metrics)
+            .service(set_weight)
+            .service(get_metadata)
+            .service(get_tags)
+            .service(set_tags)
+            .service(reclassify_images)
+            .service(get_duplicates)
+            .service(get_stats)
             .service(get_image)
+            .service(delete_image)
+            .service(move_image)
+            .default_service(web::to(|| async {
+                Err::<HttpResponse, _>(ApiError::NotFound("Not found.".to_string()))
+            }))
     })
     .bind(format!("{}:{}", config.host, config.port));
 
     // Check if the server was successfully bound
     match server {
         Ok(server) => {
-            println!("Server running at http://{}:{}", config.host, config.port); // Print a success message
-            server.run().await // Start the server
+            info!("Server running at http://{}:{}", config.host, config.port); // Print a success message
+            let result = server.run().await; // Start the server
+            // Keep the views counted since the last flush
+            final_stats.flush(&final_store);
+            result
         }
         Err(e) => {
-            println!("Failed to bind server: {}", e); // Print an error message
+            error!("Failed to bind server: {}", e); // Print an error message
             std::process::exit(1); // Exit the program
         }
     }
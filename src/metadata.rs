@@ -0,0 +1,471 @@
+use chrono::NaiveDate;
+use image::{DynamicImage, GenericImageView};
+use log::{error, info};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use crate::index::{file_name, ImageIndex};
+use crate::phash;
+use crate::stats::*;
+
+// Schema migrations, applied in order on startup, the database records how many ran
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE images (
+        filename TEXT PRIMARY KEY,
+        category TEXT NOT NULL,
+        width INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        uploaded_at INTEGER NOT NULL,
+        uploader_ip TEXT,
+        uploader_country TEXT,
+        original_filename TEXT
+    );
+    CREATE INDEX images_hash ON images (hash);",
+    "CREATE TABLE tags (
+        filename TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (filename, tag)
+    );
+    CREATE INDEX tags_tag ON tags (tag);",
+    "ALTER TABLE images ADD COLUMN phash TEXT;",
+    "CREATE TABLE redirects (
+        source TEXT PRIMARY KEY,
+        target TEXT NOT NULL
+    );",
+    "CREATE TABLE image_stats (
+        day TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        category TEXT NOT NULL,
+        views INTEGER NOT NULL,
+        picks INTEGER NOT NULL,
+        PRIMARY KEY (day, filename, category)
+    );
+    CREATE TABLE country_stats (
+        day TEXT NOT NULL,
+        country TEXT NOT NULL,
+        views INTEGER NOT NULL,
+        PRIMARY KEY (day, country)
+    );",
+];
+
+// What is known about a stored image
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ImageMetadata {
+    pub filename: String,
+    pub category: String,
+    pub width: u32,
+    pub height: u32,
+    // Size of the stored WebP file in bytes
+    pub size: u64,
+    // SHA-256 of the stored WebP file
+    pub hash: String,
+    // Unix timestamp of the upload, or of the file for images found on disk
+    pub uploaded_at: i64,
+    pub uploader_ip: Option<String>,
+    pub uploader_country: Option<String>,
+    pub original_filename: Option<String>,
+    // Perceptual hash of the pixels as 16 hex digits, close hashes are near-duplicates
+    pub phash: Option<String>,
+}
+
+impl ImageMetadata {
+    // Read the metadata of a stored image from the file itself
+    pub fn from_file(path: &Path, category: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let img = image::open(path)?;
+        let (width, height) = img.dimensions();
+        let content = fs::read(path)?;
+        let modified = fs::metadata(path)?.modified()?;
+        Ok(ImageMetadata {
+            filename: file_name(&path.to_string_lossy()).to_string(),
+            category: category.to_string(),
+            width,
+            height,
+            size: content.len() as u64,
+            hash: format!("{:x}", Sha256::digest(&content)),
+            uploaded_at: modified
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64),
+            phash: Some(phash::to_hex(phash::dhash(&img))),
+            ..Default::default()
+        })
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(ImageMetadata {
+            filename: row.get("filename")?,
+            category: row.get("category")?,
+            width: row.get("width")?,
+            height: row.get("height")?,
+            size: row.get("size")?,
+            hash: row.get("hash")?,
+            uploaded_at: row.get("uploaded_at")?,
+            uploader_ip: row.get("uploader_ip")?,
+            uploader_country: row.get("uploader_country")?,
+            original_filename: row.get("original_filename")?,
+            phash: row.get("phash")?,
+        })
+    }
+}
+
+// Hash the decoded pixels of an image, the same picture hashes the same whatever its encoding
+pub fn content_hash(img: &DynamicImage) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(img.width().to_be_bytes());
+    hasher.update(img.height().to_be_bytes());
+    hasher.update(img.to_rgba8().as_raw());
+    // Half of the hash is plenty for a filename
+    format!("{:x}", hasher.finalize())[..32].to_string()
+}
+
+// Embedded SQLite database with the metadata of every image
+pub struct MetadataStore {
+    conn: Mutex<Connection>,
+}
+
+impl MetadataStore {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        migrate(&conn)?;
+        Ok(MetadataStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn get(&self, filename: &str) -> rusqlite::Result<Option<ImageMetadata>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT * FROM images WHERE filename = ?1",
+                params![filename],
+                ImageMetadata::from_row,
+            )
+            .optional()
+    }
+
+    // Find an image by the SHA-256 of its stored file
+    pub fn find_by_hash(&self, hash: &str) -> rusqlite::Result<Option<String>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT filename FROM images WHERE hash = ?1 LIMIT 1",
+                params![hash],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    // Insert or replace the metadata of an image, upload details already known are kept
+    pub fn upsert(&self, metadata: &ImageMetadata) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO images (filename, category, width, height, size, hash, uploaded_at,
+                uploader_ip, uploader_country, original_filename, phash)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+            ON CONFLICT (filename) DO UPDATE SET
+                category = excluded.category,
+                width = excluded.width,
+                height = excluded.height,
+                size = excluded.size,
+                hash = excluded.hash,
+                uploaded_at = CASE WHEN excluded.uploader_ip IS NULL
+                    THEN uploaded_at ELSE excluded.uploaded_at END,
+                uploader_ip = COALESCE(excluded.uploader_ip, uploader_ip),
+                uploader_country = COALESCE(excluded.uploader_country, uploader_country),
+                original_filename = COALESCE(excluded.original_filename, original_filename),
+                phash = COALESCE(excluded.phash, phash)",
+            params![
+                metadata.filename,
+                metadata.category,
+                metadata.width,
+                metadata.height,
+                metadata.size,
+                metadata.hash,
+                metadata.uploaded_at,
+                metadata.uploader_ip,
+                metadata.uploader_country,
+                metadata.original_filename,
+                metadata.phash,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove(&self, filename: &str) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM images WHERE filename = ?1", params![filename])?;
+        conn.execute("DELETE FROM tags WHERE filename = ?1", params![filename])?;
+        conn.execute("DELETE FROM redirects WHERE target = ?1", params![filename])?;
+        Ok(())
+    }
+
+    // Move the metadata and tags of an image to its new name and category, optionally
+    // redirecting the old name, redirects to the old name follow it to the new one
+    pub fn rename(
+        &self,
+        filename: &str,
+        new_filename: &str,
+        category: &str,
+        redirect: bool,
+    ) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE images SET filename = ?2, category = ?3 WHERE filename = ?1",
+            params![filename, new_filename, category],
+        )?;
+        tx.execute(
+            "UPDATE tags SET filename = ?2 WHERE filename = ?1",
+            params![filename, new_filename],
+        )?;
+        if filename != new_filename {
+            // The new name is taken by a real image now
+            tx.execute("DELETE FROM redirects WHERE source = ?1", params![new_filename])?;
+            tx.execute(
+                "UPDATE redirects SET target = ?2 WHERE target = ?1",
+                params![filename, new_filename],
+            )?;
+            if redirect {
+                tx.execute(
+                    "INSERT OR REPLACE INTO redirects (source, target) VALUES (?1, ?2)",
+                    params![filename, new_filename],
+                )?;
+            }
+        }
+        tx.commit()
+    }
+
+    // Get the name an image was renamed to
+    pub fn redirect(&self, filename: &str) -> rusqlite::Result<Option<String>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT target FROM redirects WHERE source = ?1",
+                params![filename],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    // Replace the tags of an image
+    pub fn set_tags(&self, filename: &str, tags: &BTreeSet<String>) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM tags WHERE filename = ?1", params![filename])?;
+        for tag in tags {
+            tx.execute(
+                "INSERT INTO tags (filename, tag) VALUES (?1, ?2)",
+                params![filename, tag],
+            )?;
+        }
+        tx.commit()
+    }
+
+    // Get the tags of every image, loaded into the index on startup
+    pub fn all_tags(&self) -> rusqlite::Result<HashMap<String, BTreeSet<String>>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT filename, tag FROM tags")?;
+        let mut rows = stmt.query([])?;
+        let mut tags: HashMap<String, BTreeSet<String>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            tags.entry(row.get(0)?).or_default().insert(row.get(1)?);
+        }
+        Ok(tags)
+    }
+
+    // Remember the name an image had before it was converted to WebP
+    pub fn set_original_filename(&self, filename: &str, original: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE images SET original_filename = ?2 WHERE filename = ?1",
+            params![filename, original],
+        )?;
+        Ok(())
+    }
+
+    fn filenames(&self, condition: &str) -> rusqlite::Result<HashSet<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT filename FROM images WHERE {}", condition))?;
+        let filenames = stmt.query_map([], |row| row.get(0))?;
+        filenames.collect()
+    }
+
+    // Get the perceptual hash of every image that has one
+    pub fn phashes(&self) -> rusqlite::Result<Vec<(String, u64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT filename, phash FROM images WHERE phash IS NOT NULL")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+        let mut phashes = Vec::new();
+        for row in rows {
+            let (filename, hash) = row?;
+            if let Some(hash) = phash::from_hex(&hash) {
+                phashes.push((filename, hash));
+            }
+        }
+        Ok(phashes)
+    }
+
+    // Add counters to the daily statistics
+    pub fn add_stats(&self, stats: &PendingStats) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for ((day, filename, category), counts) in &stats.images {
+            tx.execute(
+                "INSERT INTO image_stats (day, filename, category, views, picks)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (day, filename, category) DO UPDATE SET
+                    views = views + excluded.views,
+                    picks = picks + excluded.picks",
+                params![day, filename, category, counts.views, counts.picks],
+            )?;
+        }
+        for ((day, country), views) in &stats.countries {
+            tx.execute(
+                "INSERT INTO country_stats (day, country, views) VALUES (?1, ?2, ?3)
+                ON CONFLICT (day, country) DO UPDATE SET views = views + excluded.views",
+                params![day, country, views],
+            )?;
+        }
+        tx.commit()
+    }
+
+    // Sum the daily statistics of a range of days, both included
+    pub fn stats(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+        limit: usize,
+    ) -> rusqlite::Result<StatsReport> {
+        let conn = self.conn.lock().unwrap();
+        let (first, last) = (from.to_string(), to.to_string());
+        let mut stmt = conn.prepare(
+            "SELECT filename, category, SUM(views) AS views, SUM(picks) AS picks
+            FROM image_stats WHERE day BETWEEN ?1 AND ?2
+            GROUP BY filename, category ORDER BY views DESC, picks DESC, filename LIMIT ?3",
+        )?;
+        let top_images = stmt
+            .query_map(params![first, last, limit], |row| {
+                Ok(ImageStats {
+                    filename: row.get(0)?,
+                    category: row.get(1)?,
+                    views: row.get(2)?,
+                    picks: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut stmt = conn.prepare(
+            "SELECT category, SUM(views) AS views, SUM(picks) AS picks
+            FROM image_stats WHERE day BETWEEN ?1 AND ?2
+            GROUP BY category ORDER BY views DESC, category",
+        )?;
+        let categories = stmt
+            .query_map(params![first, last], |row| {
+                Ok(CategoryStats {
+                    category: row.get(0)?,
+                    views: row.get(1)?,
+                    picks: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut stmt = conn.prepare(
+            "SELECT country, SUM(views) AS views
+            FROM country_stats WHERE day BETWEEN ?1 AND ?2
+            GROUP BY country ORDER BY views DESC, country",
+        )?;
+        let countries = stmt
+            .query_map(params![first, last], |row| {
+                Ok(CountryStats {
+                    country: row.get(0)?,
+                    views: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(StatsReport {
+            from,
+            to,
+            views: categories.iter().map(|c| c.views).sum(),
+            picks: categories.iter().map(|c| c.picks).sum(),
+            top_images,
+            categories,
+            countries,
+        })
+    }
+
+    // Record an image found on disk, the upload details of a known image are kept
+    pub fn record_file(&self, path: &Path, category: &str) -> bool {
+        match ImageMetadata::from_file(path, category) {
+            Ok(metadata) => match self.upsert(&metadata) {
+                Ok(_) => true,
+                Err(e) => {
+                    error!("Failed to store metadata of {}: {}", metadata.filename, e);
+                    false
+                }
+            },
+            Err(e) => {
+                error!("Failed to read metadata of {:?}: {}", path, e);
+                false
+            }
+        }
+    }
+
+    // Bring the store in line with the index: record new images and drop deleted ones
+    pub fn backfill(&self, index: &ImageIndex) -> rusqlite::Result<(usize, usize)> {
+        let known = self.filenames("1")?;
+        // Rows recorded before perceptual hashes existed are completed as well
+        let incomplete = self.filenames("phash IS NULL")?;
+        let mut indexed = HashSet::new();
+        let mut added = 0;
+        for (category, path) in index.entries() {
+            let filename = file_name(path);
+            indexed.insert(filename.to_string());
+            if known.contains(filename) {
+                if incomplete.contains(filename) {
+                    self.record_file(Path::new(path), category);
+                }
+            } else if self.record_file(Path::new(path), category) {
+                added += 1;
+            }
+        }
+        // Only rows of files gone from a folder that is still there are dropped, a category
+        // missing from the config or a folder that cannot be read keeps its tags and history
+        let mut removed = 0;
+        for filename in known.difference(&indexed) {
+            let category = self.get(filename)?.map(|metadata| metadata.category);
+            let folder = match category.as_deref().and_then(|c| index.folder(c)) {
+                Some(folder) => folder,
+                None => continue,
+            };
+            let gone = folder.is_dir()
+                && matches!(
+                    fs::metadata(folder.join(filename)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound
+                );
+            if gone {
+                self.remove(filename)?;
+                removed += 1;
+            }
+        }
+        Ok((added, removed))
+    }
+}
+
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(&format!(
+            "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+            migration,
+            i + 1
+        ))?;
+        info!("Metadata store migrated to version {}.", i + 1);
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::Error;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+// Metrics of the whole process, exposed on /metrics
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    // Requests by route pattern, method and status
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub response_bytes: IntCounterVec,
+    // Random images served by category
+    pub random_picks: IntCounterVec,
+    // Uploaded files by result, success, duplicate or the code of the error
+    pub uploads: IntCounterVec,
+    pub thumbnail_duration: Histogram,
+    // Set from the index when the metrics are scraped
+    pub images: IntGaugeVec,
+    // Lookups of the ETag and rendition caches by result, hit or miss
+    pub cache_lookups: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time spent handling requests"),
+            &["route", "method"],
+        )
+        .unwrap();
+        let response_bytes = IntCounterVec::new(
+            Opts::new("http_response_bytes_total", "Bytes of the response bodies"),
+            &["route"],
+        )
+        .unwrap();
+        let random_picks = IntCounterVec::new(
+            Opts::new("random_picks_total", "Random images picked"),
+            &["category"],
+        )
+        .unwrap();
+        let uploads = IntCounterVec::new(
+            Opts::new("uploads_total", "Uploaded files, by result"),
+            &["result"],
+        )
+        .unwrap();
+        let thumbnail_duration = Histogram::with_opts(
+            HistogramOpts::new("thumbnail_duration_seconds", "Time spent creating thumbnails")
+                .buckets(vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+        )
+        .unwrap();
+        let images = IntGaugeVec::new(
+            Opts::new("images_indexed", "Images in the index"),
+            &["category"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("cache_lookups_total", "Lookups of the ETag and rendition caches"),
+            &["cache", "result"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(request_duration.clone())).unwrap();
+        registry.register(Box::new(response_bytes.clone())).unwrap();
+        registry.register(Box::new(random_picks.clone())).unwrap();
+        registry.register(Box::new(uploads.clone())).unwrap();
+        registry.register(Box::new(thumbnail_duration.clone())).unwrap();
+        registry.register(Box::new(images.clone())).unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            response_bytes,
+            random_picks,
+            uploads,
+            thumbnail_duration,
+            images,
+            cache_lookups,
+        }
+    }
+
+    // Count a lookup of a cache
+    pub fn cache_lookup(&self, cache: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache_lookups.with_label_values(&[cache, result]).inc();
+    }
+
+    // Encode every metric in the Prometheus text format
+    pub fn encode(&self) -> Result<String, String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| e.to_string())?;
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
+}
+
+// Get the label of a method, clients can send any method name so unknown ones share a label
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        _ => "OTHER",
+    }
+}
+
+// Middleware counting the requests, their duration and the bytes sent per route
+pub fn track<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>> + 'static
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    let start = Instant::now();
+    let method = method_label(req.method());
+    let fut = srv.call(req);
+    async move {
+        let res = fut.await;
+        // Label by route pattern, not path, to keep the number of series bounded
+        let (route, status, bytes) = match &res {
+            Ok(res) => (
+                res.request()
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string()),
+                res.status().as_u16(),
+                match res.response().body().size() {
+                    BodySize::Sized(bytes) => bytes,
+                    _ => 0,
+                },
+            ),
+            Err(e) => (
+                "unmatched".to_string(),
+                e.as_response_error().status_code().as_u16(),
+                0,
+            ),
+        };
+        let metrics = &*METRICS;
+        metrics
+            .requests
+            .with_label_values(&[&route, method, &status.to_string()])
+            .inc();
+        metrics
+            .request_duration
+            .with_label_values(&[&route, method])
+            .observe(start.elapsed().as_secs_f64());
+        metrics.response_bytes.with_label_values(&[&route]).inc_by(bytes);
+        res
+    }
+}
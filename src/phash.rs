@@ -0,0 +1,70 @@
+use image::imageops::{self, FilterType};
+use image::DynamicImage;
+use std::collections::HashMap;
+
+// Difference hash: one bit per pair of horizontally adjacent pixels of a 9x8 grayscale
+// thumbnail, set when the brightness increases, so resizing and re-encoding barely change it
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = imageops::resize(&img.to_luma8(), 9, 8, FilterType::Triangle);
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+// Number of differing bits between two hashes
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+pub fn to_hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+pub fn from_hex(hash: &str) -> Option<u64> {
+    u64::from_str_radix(hash, 16).ok()
+}
+
+// Group images whose hashes are within the distance of each other, directly or through
+// other images of the group, only groups of two or more images are returned
+pub fn clusters(hashes: &[(String, u64)], max_distance: u32) -> Vec<Vec<String>> {
+    // Union-find over the images
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if distance(hashes[i].1, hashes[j].1) <= max_distance {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a] = b;
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<String>> = HashMap::new();
+    for (i, (filename, _)) in hashes.iter().enumerate() {
+        let r = root(&mut parent, i);
+        groups.entry(r).or_default().push(filename.clone());
+    }
+    let mut clusters: Vec<Vec<String>> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort();
+            group
+        })
+        .collect();
+    clusters.sort();
+    clusters
+}
@@ -0,0 +1,430 @@
+use chrono::{DateTime, Datelike, Utc};
+use chrono_tz::Tz;
+use md5::{Digest, Md5};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::filter::{FilterQuery, ImageFilter, Orientation};
+use crate::index::{file_name, ImageIndex};
+use crate::lru::LruMap;
+use crate::utils::ShuffleConfig;
+
+// Length of the time bucket during which every visitor gets the same image
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Hour,
+    Day,
+    Week,
+}
+
+impl Period {
+    // Get the key of the bucket containing the given time, in the configured timezone
+    pub fn bucket(&self, now: DateTime<Utc>, timezone: Tz) -> String {
+        let now = now.with_timezone(&timezone);
+        match self {
+            Period::Hour => now.format("%Y-%m-%dT%H").to_string(),
+            Period::Day => now.format("%Y-%m-%d").to_string(),
+            Period::Week => {
+                let week = now.iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            }
+        }
+    }
+}
+
+// How the random endpoint answers with the picked image
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RandomMode {
+    // Send the image itself
+    Body,
+    // Redirect to /api/image/{filename} so the image can be cached on its own
+    Redirect,
+}
+
+// Query parameters of /api/images/{category}
+#[derive(Deserialize, Debug, Default)]
+pub struct RandomQuery {
+    pub seed: Option<String>,
+    pub period: Option<Period>,
+    pub mode: Option<RandomMode>,
+    // Cycle through the whole category before repeating an image
+    #[serde(default)]
+    pub shuffle: bool,
+    // Identifies the client of the shuffle bag instead of the cookie, implies shuffle, only
+    // ids issued by the server are accepted, other ones get a new id in X-Shuffle-Client
+    pub client: Option<String>,
+    // Comma separated tags the image must all have, 'tag' is an alias
+    #[serde(alias = "tag")]
+    pub tags: Option<String>,
+    // Comma separated tags the image must not have
+    pub exclude_tags: Option<String>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub orientation: Option<Orientation>,
+    // Aspect ratio like 16:9, optionally with a relative tolerance like 16:9~0.05
+    pub aspect: Option<String>,
+}
+
+impl RandomQuery {
+    // Get the key the pick is derived from, None for a truly random pick
+    fn key(&self, now: DateTime<Utc>, timezone: Tz) -> Option<String> {
+        match (&self.seed, self.period) {
+            (None, None) => None,
+            (seed, period) => Some(format!(
+                "{}:{}",
+                period.map(|p| p.bucket(now, timezone)).unwrap_or_default(),
+                seed.as_deref().unwrap_or("")
+            )),
+        }
+    }
+
+    // Get the filter of the images to pick from, the query parameters are the same as the list's
+    pub fn filter(&self) -> Result<ImageFilter, String> {
+        FilterQuery {
+            tags: self.tags.clone(),
+            exclude_tags: self.exclude_tags.clone(),
+            min_width: self.min_width,
+            min_height: self.min_height,
+            orientation: self.orientation,
+            aspect: self.aspect.clone(),
+        }
+        .filter()
+    }
+
+    // Pick an index with the sampler, the same seed and period always give the same index
+    pub fn pick(&self, sampler: &AliasTable, timezone: Tz) -> usize {
+        let (column, coin) = match self.key(Utc::now(), timezone) {
+            Some(key) => {
+                let hash: [u8; 16] = Md5::digest(key.as_bytes()).into();
+                let column = u64::from_be_bytes(hash[..8].try_into().unwrap());
+                let coin = u64::from_be_bytes(hash[8..].try_into().unwrap());
+                // The top 53 bits make a uniform float in [0, 1)
+                (column as usize, (coin >> 11) as f64 / (1u64 << 53) as f64)
+            }
+            None => {
+                let mut rng = rand::thread_rng();
+                (rng.gen(), rng.gen())
+            }
+        };
+        sampler.sample(column, coin)
+    }
+}
+
+// Walker's alias table, samples weighted indices in constant time
+#[derive(Debug, Default, Clone)]
+pub struct AliasTable {
+    probability: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    // Build the table with Vose's method, weights that are not positive are never sampled
+    pub fn new(weights: &[f64]) -> Self {
+        let weights: Vec<f64> = weights
+            .iter()
+            .map(|&w| if w.is_finite() && w > 0.0 { w } else { 0.0 })
+            .collect();
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return AliasTable::default();
+        }
+
+        let len = weights.len();
+        let mut scaled: Vec<f64> = weights.iter().map(|w| w * len as f64 / total).collect();
+        let mut probability = vec![1.0; len];
+        let mut alias: Vec<usize> = (0..len).collect();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..len).partition(|&i| scaled[i] < 1.0);
+        while let (Some(s), Some(l)) = (small.pop(), large.pop()) {
+            probability[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] += scaled[s] - 1.0;
+            if scaled[l] < 1.0 {
+                small.push(l);
+            } else {
+                large.push(l);
+            }
+        }
+        // What is left over only differs from 1 by rounding errors, zero weights must still lose
+        let positive = weights.iter().position(|&w| w > 0.0).unwrap_or(0);
+        for i in small {
+            if weights[i] == 0.0 {
+                probability[i] = 0.0;
+                alias[i] = positive;
+            }
+        }
+        AliasTable { probability, alias }
+    }
+
+    // Check if there is nothing to sample
+    pub fn is_empty(&self) -> bool {
+        self.probability.is_empty()
+    }
+
+    // Sample an index from a uniform column and a coin flip in [0, 1)
+    pub fn sample(&self, column: usize, coin: f64) -> usize {
+        let column = column % self.probability.len();
+        if coin < self.probability[column] {
+            column
+        } else {
+            self.alias[column]
+        }
+    }
+}
+
+// Header carrying the id of the shuffle client, to be sent back in the client parameter
+pub const SHUFFLE_CLIENT_HEADER: &str = "x-shuffle-client";
+
+// Shuffled positions left to serve to a client in a category
+struct ShuffleBag {
+    remaining: Vec<u32>,
+    // Position served last, not repeated at the start of the next cycle
+    last: Option<u32>,
+    // Generation of the index the positions refer to
+    generation: u64,
+    last_used: Instant,
+}
+
+// Shuffle bags of a client, one per category and filter
+struct ClientBags {
+    bags: HashMap<String, ShuffleBag>,
+    last_used: Instant,
+}
+
+impl ClientBags {
+    // Memory the bags take, counted in positions, an empty bag or client counts as one
+    fn weight(&self) -> usize {
+        1 + self.bags.values().map(|bag| bag.remaining.len() + 1).sum::<usize>()
+    }
+}
+
+// Shuffle bags of the clients the server issued an id to, bounded by the total number of
+// positions and dropped when idle
+pub struct ShuffleBags {
+    clients: Mutex<LruMap<String, ClientBags>>,
+    idle_timeout: Duration,
+}
+
+impl ShuffleBags {
+    pub fn new(config: &ShuffleConfig) -> Self {
+        ShuffleBags {
+            clients: Mutex::new(LruMap::new(config.max_entries)),
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+        }
+    }
+
+    // Get the client of a request from its id, ids the server did not issue and ids whose
+    // bags were dropped are replaced by a new one, returns the client and if it is new
+    pub fn client(&self, id: Option<&str>) -> (String, bool) {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        clients.remove_stale(|client| now.duration_since(client.last_used) >= self.idle_timeout);
+        if let Some(id) = id {
+            if clients.get_mut(&id.to_string()).is_some() {
+                return (id.to_string(), false);
+            }
+        }
+        let id = new_client_id();
+        let client = ClientBags {
+            bags: HashMap::new(),
+            last_used: now,
+        };
+        clients.insert(id.clone(), client, 1);
+        (id, true)
+    }
+
+    // Take the next position of the client's bag, refilled with the category once empty
+    pub fn next(
+        &self,
+        client: &str,
+        category: &str,
+        filter: &ImageFilter,
+        index: &ImageIndex,
+    ) -> Option<usize> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let id = client.to_string();
+        if clients.get_mut(&id).is_none() {
+            // Dropped since it was looked up, it starts over with new bags
+            let client = ClientBags {
+                bags: HashMap::new(),
+                last_used: now,
+            };
+            clients.insert(id.clone(), client, 1);
+        }
+        let client = clients.get_mut(&id)?;
+        client.last_used = now;
+        let bag = client
+            .bags
+            .entry(format!("{}?{}", category, filter.key()))
+            .or_insert_with(|| ShuffleBag {
+                remaining: Vec::new(),
+                last: None,
+                generation: index.generation(),
+                last_used: now,
+            });
+
+        // A changed index or an idle client starts a new cycle
+        if bag.generation != index.generation()
+            || now.duration_since(bag.last_used) >= self.idle_timeout
+        {
+            bag.remaining.clear();
+        }
+        bag.last_used = now;
+        if bag.remaining.is_empty() {
+            // Images weighing 0 are never picked
+            bag.remaining = (0..index.count(category) as u32)
+                .filter(|&i| {
+                    index.nth(category, i as usize).is_some_and(|path| {
+                        index.weight(file_name(path)) > 0.0 && filter.matches(index, path)
+                    })
+                })
+                .collect();
+            bag.remaining.shuffle(&mut rand::thread_rng());
+            let end = bag.remaining.len().saturating_sub(1);
+            if end > 0 && bag.remaining.last() == bag.last.as_ref() {
+                bag.remaining.swap(0, end);
+            }
+            bag.generation = index.generation();
+        }
+        // Tags may have changed since the bag was filled
+        let mut next = None;
+        while let Some(i) = bag.remaining.pop() {
+            if index
+                .nth(category, i as usize)
+                .is_some_and(|path| filter.matches(index, path))
+            {
+                bag.last = Some(i);
+                next = Some(i as usize);
+                break;
+            }
+        }
+        let weight = client.weight();
+        clients.set_weight(&id, weight);
+        next
+    }
+}
+
+// Generate an identifier for a client without one
+pub fn new_client_id() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Category;
+    use std::path::Path;
+
+    // Count the picks of every column with evenly spaced coin flips
+    fn frequencies(table: &AliasTable, len: usize, flips: usize) -> Vec<f64> {
+        let mut counts = vec![0usize; len];
+        for column in 0..len {
+            for flip in 0..flips {
+                counts[table.sample(column, (flip as f64 + 0.5) / flips as f64)] += 1;
+            }
+        }
+        counts.iter().map(|&n| n as f64 / (len * flips) as f64).collect()
+    }
+
+    #[test]
+    fn alias_table_without_positive_weights_is_empty() {
+        assert!(AliasTable::new(&[]).is_empty());
+        assert!(AliasTable::new(&[0.0, 0.0, 0.0]).is_empty());
+        assert!(AliasTable::new(&[0.0, -1.0, f64::NAN]).is_empty());
+        assert!(!AliasTable::new(&[0.0, 1.0]).is_empty());
+    }
+
+    #[test]
+    fn alias_table_never_samples_zero_weights() {
+        let weights = [0.0, 3.0, 0.0, 1.0, 0.0, f64::INFINITY, 0.5];
+        let table = AliasTable::new(&weights);
+        for (i, frequency) in frequencies(&table, weights.len(), 1000).iter().enumerate() {
+            if !(weights[i].is_finite() && weights[i] > 0.0) {
+                assert_eq!(*frequency, 0.0, "weight {} was sampled", weights[i]);
+            }
+        }
+        // The edges of the coin range as well
+        for column in 0..weights.len() {
+            for coin in [0.0, 1.0 - f64::EPSILON] {
+                let i = table.sample(column, coin);
+                assert!(weights[i] > 0.0 && weights[i].is_finite());
+            }
+        }
+    }
+
+    #[test]
+    fn alias_table_frequencies_match_weights() {
+        let weights = [1.0, 2.0, 3.0, 4.0, 0.0, 10.0];
+        let total: f64 = weights.iter().sum();
+        let table = AliasTable::new(&weights);
+        for (i, frequency) in frequencies(&table, weights.len(), 10_000).iter().enumerate() {
+            let expected = weights[i] / total;
+            assert!(
+                (frequency - expected).abs() < 1e-3,
+                "index {} sampled {} instead of {}",
+                i,
+                frequency,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn only_issued_client_ids_are_accepted() {
+        let bags = ShuffleBags::new(&ShuffleConfig::default());
+        let (issued, is_new) = bags.client(None);
+        assert!(is_new);
+        assert_eq!(bags.client(Some(&issued)), (issued.clone(), false));
+        let (replaced, is_new) = bags.client(Some("made-up"));
+        assert!(is_new);
+        assert_ne!(replaced, "made-up");
+    }
+
+    #[test]
+    fn a_client_cycles_through_its_bag() {
+        let categories = vec![Category {
+            name: "pc".to_string(),
+            folder: "pc".to_string(),
+            description: String::new(),
+            mode: None,
+            min_aspect: None,
+            max_aspect: None,
+        }];
+        let mut index = ImageIndex::new("./images", &categories);
+        for i in 0..5 {
+            index.insert(Path::new(&format!("./images/pc/{}.webp", i)));
+        }
+        let bags = ShuffleBags::new(&ShuffleConfig::default());
+        let (client, _) = bags.client(None);
+        let filter = ImageFilter::default();
+        for _ in 0..3 {
+            let mut cycle: Vec<usize> = (0..5)
+                .map(|_| bags.next(&client, "pc", &filter, &index).unwrap())
+                .collect();
+            cycle.sort();
+            assert_eq!(cycle, vec![0, 1, 2, 3, 4]);
+        }
+        assert_eq!(bags.client(Some(&client)), (client, false));
+    }
+
+    #[test]
+    fn clients_are_bounded_by_entries() {
+        let config = ShuffleConfig {
+            max_entries: 3,
+            ..ShuffleConfig::default()
+        };
+        let bags = ShuffleBags::new(&config);
+        let (first, _) = bags.client(None);
+        for _ in 0..3 {
+            bags.client(None);
+        }
+        assert!(bags.client(Some(&first)).1);
+    }
+}
@@ -0,0 +1,238 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, Error, ResponseError};
+use ipnet::IpNet;
+use log::debug;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::client_ip::ClientIp;
+use crate::error::ApiError;
+use crate::lru::LruMap;
+use crate::utils::{RateLimit, RateLimitConfig};
+
+// Routes sharing a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    // Random images, each one is read from disk
+    Random,
+    // Images and thumbnails by name
+    Static,
+    Upload,
+    // Requests answered 401, only counted for requests with credentials
+    FailedAuth,
+}
+
+impl RouteGroup {
+    // Get the group of a request from its method and path, None for unlimited routes
+    fn of(method: &Method, path: &str) -> Option<RouteGroup> {
+        if path.starts_with("/api/images/") {
+            if method == Method::POST {
+                Some(RouteGroup::Upload)
+            } else {
+                Some(RouteGroup::Random)
+            }
+        } else if path.starts_with("/api/image/") || path.starts_with("/api/thumbnail/") {
+            Some(RouteGroup::Static)
+        } else {
+            None
+        }
+    }
+
+    fn limit(self, config: &RateLimitConfig) -> &RateLimit {
+        match self {
+            RouteGroup::Random => &config.random,
+            RouteGroup::Static => &config.static_files,
+            RouteGroup::Upload => &config.upload,
+            RouteGroup::FailedAuth => &config.failed_auth,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // When the bucket will be full again, a full bucket is the same as no bucket
+    full_at: Option<Instant>,
+}
+
+impl Bucket {
+    // Add the tokens earned since the last update
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated = now;
+    }
+
+    fn update_full_at(&mut self, limit: &RateLimit) {
+        let missing = limit.burst - self.tokens;
+        self.full_at = if missing <= 0.0 {
+            Some(self.updated)
+        } else if limit.rate > 0.0 {
+            Some(self.updated + Duration::from_secs_f64(missing / limit.rate))
+        } else {
+            None
+        };
+    }
+}
+
+// Get the key of the buckets of a client, an IPv6 client usually owns a whole prefix
+fn client_key(ip: Option<IpAddr>, ipv6_prefix: u8) -> IpNet {
+    let ip = match ip {
+        Some(IpAddr::V6(v6)) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+        Some(ip) => ip,
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    match ip {
+        IpAddr::V6(_) => IpNet::new(ip, ipv6_prefix.min(128))
+            .map(|net| net.trunc())
+            .unwrap_or_else(|_| IpNet::from(ip)),
+        IpAddr::V4(_) => IpNet::from(ip),
+    }
+}
+
+// Token buckets per client and route group
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<LruMap<(RouteGroup, IpNet), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            config: config.clone(),
+            buckets: Mutex::new(LruMap::new(config.max_clients)),
+        }
+    }
+
+    // Take a token from the bucket of a client, or check that one is left without taking it,
+    // returns the seconds to wait when the bucket is empty
+    fn acquire(&self, group: RouteGroup, client: IpNet, take: bool) -> Result<(), u64> {
+        let limit = group.limit(&self.config);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // Buckets that filled up again are dropped, the least recently used ones first
+        buckets.remove_stale(|bucket| bucket.full_at.is_some_and(|full_at| full_at <= now));
+        let key = (group, client);
+        if buckets.get_mut(&key).is_none() {
+            let bucket = Bucket {
+                tokens: limit.burst,
+                updated: now,
+                full_at: Some(now),
+            };
+            buckets.insert(key, bucket, 1);
+        }
+        let bucket = match buckets.get_mut(&key) {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        bucket.refill(limit, now);
+        let result = if bucket.tokens >= 1.0 {
+            if take {
+                bucket.tokens -= 1.0;
+            }
+            Ok(())
+        } else if limit.rate > 0.0 {
+            Err(((1.0 - bucket.tokens) / limit.rate).ceil().max(1.0) as u64)
+        } else {
+            // A group without refill stays closed until the server restarts
+            Err(u64::from(u32::MAX))
+        };
+        bucket.update_full_at(limit);
+        result
+    }
+}
+
+// Middleware answering 429 to clients over the limit of the route group, or that failed to
+// authenticate too often
+pub fn limit<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+    limiter: web::Data<RateLimiter>,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, Error>> + 'static
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    let ip = ClientIp::of(req.request());
+    let client = client_key(ip.ip, limiter.config.ipv6_prefix);
+    let authenticating = req.headers().contains_key(header::AUTHORIZATION);
+    let mut denied = None;
+    if limiter.config.enabled {
+        // Clients guessing credentials are locked out of every route that takes them
+        if authenticating {
+            denied = limiter.acquire(RouteGroup::FailedAuth, client, false).err();
+        }
+        if denied.is_none() {
+            if let Some(group) = RouteGroup::of(req.method(), req.path()) {
+                denied = limiter.acquire(group, client, true).err();
+            }
+        }
+    }
+    let call = match denied {
+        Some(retry_after) => {
+            debug!("Rate limited {} {} from {}", req.method(), req.path(), ip);
+            Err(req.into_response(ApiError::TooManyRequests(retry_after).error_response()))
+        }
+        None => Ok(srv.call(req)),
+    };
+    async move {
+        match call {
+            Err(res) => Ok(res.map_into_right_body()),
+            Ok(fut) => {
+                let res = fut.await?;
+                let failed = authenticating && res.status() == StatusCode::UNAUTHORIZED;
+                if limiter.config.enabled && failed {
+                    let _ = limiter.acquire(RouteGroup::FailedAuth, client, true);
+                }
+                Ok(res.map_into_left_body())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_clients_share_their_prefix() {
+        let a = client_key(Some("2001:db8:1:2:aaaa::1".parse().unwrap()), 64);
+        let b = client_key(Some("2001:db8:1:2:bbbb::9".parse().unwrap()), 64);
+        let c = client_key(Some("2001:db8:1:3::1".parse().unwrap()), 64);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(a.to_string(), "2001:db8:1:2::/64");
+    }
+
+    #[test]
+    fn ipv4_clients_are_keyed_by_address() {
+        let a = client_key(Some("192.0.2.1".parse().unwrap()), 64);
+        let mapped = client_key(Some("::ffff:192.0.2.1".parse().unwrap()), 64);
+        assert_eq!(a, mapped);
+        assert_eq!(a.to_string(), "192.0.2.1/32");
+    }
+
+    #[test]
+    fn bucket_empties_and_bounds_clients() {
+        let config = RateLimitConfig {
+            random: RateLimit { rate: 0.001, burst: 2.0 },
+            max_clients: 2,
+            ..RateLimitConfig::default()
+        };
+        let limiter = RateLimiter::new(&config);
+        let client = client_key(Some("192.0.2.1".parse().unwrap()), 64);
+        assert!(limiter.acquire(RouteGroup::Random, client, true).is_ok());
+        assert!(limiter.acquire(RouteGroup::Random, client, true).is_ok());
+        assert!(limiter.acquire(RouteGroup::Random, client, true).is_err());
+        for i in 0..10 {
+            let other = client_key(Some(format!("198.51.100.{}", i).parse().unwrap()), 64);
+            let _ = limiter.acquire(RouteGroup::Random, other, true);
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,331 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageError, ImageFormat, ImageResult};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::metrics::METRICS;
+use crate::utils::RenditionConfig;
+
+// How the image is fitted into the requested box
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    // Fit inside the box keeping the aspect ratio, never upscaled
+    #[default]
+    Contain,
+    // Fill the box keeping the aspect ratio, cropping the overflow
+    Cover,
+    // Stretch to exactly the box
+    Fill,
+}
+
+impl Fit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+// Query parameters of /api/image/{filename}
+#[derive(Deserialize, Debug, Default)]
+pub struct ImageQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    #[serde(default)]
+    pub fit: Fit,
+}
+
+impl ImageQuery {
+    // Check if a rendition is requested instead of the original
+    pub fn is_resized(&self) -> bool {
+        self.w.is_some() || self.h.is_some()
+    }
+
+    // Check the requested size against the configured limits
+    pub fn validate(&self, config: &RenditionConfig) -> Result<(), String> {
+        if self.w == Some(0) || self.h == Some(0) {
+            return Err("Width and height must be greater than 0.".to_string());
+        }
+        if self.w.unwrap_or(0) > config.max_width || self.h.unwrap_or(0) > config.max_height {
+            return Err(format!(
+                "Requested size exceeds the maximum of {}x{}.",
+                config.max_width, config.max_height
+            ));
+        }
+        if let Some(largest) = config.sizes.iter().max() {
+            if self.w.unwrap_or(0) > *largest || self.h.unwrap_or(0) > *largest {
+                return Err(format!("Requested size exceeds the largest size of {}.", largest));
+            }
+        }
+        Ok(())
+    }
+
+    // Round the requested size up to a configured one, so that only a few renditions of an
+    // image are ever created
+    pub fn snap(&mut self, config: &RenditionConfig) {
+        self.w = self.w.map(|w| snap_size(w, config, config.max_width));
+        self.h = self.h.map(|h| snap_size(h, config, config.max_height));
+    }
+}
+
+// Get the smallest allowed size not below the requested one
+fn snap_size(size: u32, config: &RenditionConfig, max: u32) -> u32 {
+    if !config.sizes.is_empty() {
+        return config.sizes.iter().copied().filter(|&s| s >= size).min().unwrap_or(size);
+    }
+    if config.step > 1 {
+        return size.div_ceil(config.step).saturating_mul(config.step).min(max);
+    }
+    size
+}
+
+// Formats an image can be served in, the stored images are all WebP
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    WebP,
+    Jpeg,
+    Png,
+}
+
+impl OutputFormat {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "webp",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+        }
+    }
+
+    // Pick the format with the highest quality in the Accept header, WebP wins ties
+    pub fn negotiate(req: &HttpRequest) -> OutputFormat {
+        let accept = match req.headers().get(header::ACCEPT) {
+            Some(accept) => accept.to_str().unwrap_or(""),
+            None => return OutputFormat::WebP,
+        };
+        let mut best = (OutputFormat::WebP, 0.0);
+        for format in [OutputFormat::WebP, OutputFormat::Jpeg, OutputFormat::Png] {
+            let quality = accept_quality(accept, format.mime());
+            if quality > best.1 {
+                best = (format, quality);
+            }
+        }
+        best.0
+    }
+}
+
+// Get the quality the Accept header gives a mime type, the most specific range applies
+fn accept_quality(accept: &str, mime: &str) -> f32 {
+    let (mime_type, _) = mime.split_once('/').unwrap_or((mime, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media_range = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let specificity = if media_range == mime {
+            3
+        } else if media_range == format!("{}/*", mime_type) {
+            2
+        } else if media_range == "*/*" {
+            1
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(s, _)| specificity > s) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+// Get the cache path of a rendition, renditions of an image share a folder named after it
+pub fn rendition_path(
+    image_path: &Path,
+    query: &ImageQuery,
+    format: OutputFormat,
+    cache_folder: &str,
+) -> PathBuf {
+    let file_name = image_path.file_name().unwrap_or_default();
+    Path::new(cache_folder).join(file_name).join(format!(
+        "{}x{}_{}.{}",
+        query.w.unwrap_or(0),
+        query.h.unwrap_or(0),
+        query.fit.as_str(),
+        format.extension()
+    ))
+}
+
+// Create the rendition of an image unless an up-to-date one is cached, returns its path
+pub fn create_rendition(
+    image_path: &Path,
+    query: &ImageQuery,
+    format: OutputFormat,
+    cache_folder: &str,
+) -> ImageResult<PathBuf> {
+    let rendition_path = rendition_path(image_path, query, format, cache_folder);
+    render(image_path, &rendition_path, format, |img| resize(img, query))
+}
+
+// Transcode a thumbnail to another format unless an up-to-date one is cached, returns its path
+pub fn transcode_thumbnail(
+    thumbnail_path: &Path,
+    format: OutputFormat,
+    cache_folder: &str,
+) -> ImageResult<PathBuf> {
+    let file_name = thumbnail_path.file_name().unwrap_or_default();
+    let transcoded_path = Path::new(cache_folder)
+        .join(file_name)
+        .join(format!("thumbnail.{}", format.extension()));
+    render(thumbnail_path, &transcoded_path, format, |img| img)
+}
+
+// Transform an image and save it in the given format, reusing the target if it is up to date
+fn render(
+    source: &Path,
+    target: &Path,
+    format: OutputFormat,
+    transform: impl FnOnce(DynamicImage) -> DynamicImage,
+) -> ImageResult<PathBuf> {
+    // Reuse the cached file if it is newer than the source
+    if let (Ok(cached), Ok(original)) = (fs::metadata(target), fs::metadata(source)) {
+        if cached.modified()? >= original.modified()? {
+            METRICS.cache_lookup("rendition", true);
+            return Ok(target.to_path_buf());
+        }
+    }
+    METRICS.cache_lookup("rendition", false);
+
+    let img = transform(image::open(source)?);
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Encode next to the target and move it into place, so that a half written or failed
+    // file is never served and concurrent requests do not write to the same file
+    let temp = temp_path(target);
+    let saved = match format {
+        OutputFormat::WebP => img.save_with_format(&temp, ImageFormat::WebP),
+        // JPEG has no alpha channel
+        OutputFormat::Jpeg => {
+            DynamicImage::ImageRgb8(img.to_rgb8()).save_with_format(&temp, ImageFormat::Jpeg)
+        }
+        OutputFormat::Png => img.save_with_format(&temp, ImageFormat::Png),
+    };
+    if let Err(e) = saved.and_then(|_| fs::rename(&temp, target).map_err(ImageError::from)) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(target.to_path_buf())
+}
+
+// Get a unique hidden path in the folder of a file to write it before renaming
+fn temp_path(target: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let file_name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+// Check if rendering failed because the source file is missing
+pub fn is_not_found(e: &ImageError) -> bool {
+    matches!(e, ImageError::IoError(e) if e.kind() == std::io::ErrorKind::NotFound)
+}
+
+// Resize an image into the box requested by the query
+fn resize(img: DynamicImage, query: &ImageQuery) -> DynamicImage {
+    let (orig_width, orig_height) = img.dimensions();
+    let ratio = f64::from(orig_width) / f64::from(orig_height);
+
+    // Derive the missing side of the box from the aspect ratio
+    let (width, height) = match (query.w, query.h) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, (f64::from(w) / ratio).round().max(1.0) as u32),
+        (None, Some(h)) => ((f64::from(h) * ratio).round().max(1.0) as u32, h),
+        (None, None) => (orig_width, orig_height),
+    };
+
+    match query.fit {
+        Fit::Contain => {
+            let scale = (f64::from(width) / f64::from(orig_width))
+                .min(f64::from(height) / f64::from(orig_height))
+                .min(1.0);
+            if scale == 1.0 {
+                return img;
+            }
+            let new_width = (f64::from(orig_width) * scale).round().max(1.0) as u32;
+            let new_height = (f64::from(orig_height) * scale).round().max(1.0) as u32;
+            img.resize_exact(new_width, new_height, FilterType::Lanczos3)
+        }
+        Fit::Cover => img.resize_to_fill(width, height, FilterType::Lanczos3),
+        Fit::Fill => img.resize_exact(width, height, FilterType::Lanczos3),
+    }
+}
+
+// Remove every cached rendition of an image
+pub fn remove_renditions(image_path: &Path, cache_folder: &str) -> std::io::Result<()> {
+    let file_name = match image_path.file_name() {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    match fs::remove_dir_all(Path::new(cache_folder).join(file_name)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_snap_up_to_the_step_or_the_allowlist() {
+        let mut config = RenditionConfig {
+            step: 100,
+            max_width: 1920,
+            ..RenditionConfig::default()
+        };
+        assert_eq!(snap_size(1, &config, 1920), 100);
+        assert_eq!(snap_size(200, &config, 1920), 200);
+        assert_eq!(snap_size(1901, &config, 1920), 1920);
+        config.sizes = vec![320, 640, 1280];
+        assert_eq!(snap_size(100, &config, 1920), 320);
+        assert_eq!(snap_size(641, &config, 1920), 1280);
+        let query = ImageQuery {
+            w: Some(1281),
+            ..ImageQuery::default()
+        };
+        assert!(query.validate(&config).is_err());
+    }
+
+    #[test]
+    fn failed_renders_leave_no_file() {
+        let folder = std::env::temp_dir().join(format!("rendition-test-{}", std::process::id()));
+        let source = folder.join("missing.webp");
+        let target = folder.join("out").join("100x0_contain.webp");
+        let result = render(&source, &target, OutputFormat::WebP, |img| img);
+        assert!(result.is_err_and(|e| is_not_found(&e)));
+        assert!(!target.exists());
+        let _ = fs::remove_dir_all(&folder);
+    }
+}
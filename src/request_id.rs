@@ -0,0 +1,59 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use rand::Rng;
+use std::future::Future;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    // Identifies the request being handled, for error bodies and logs
+    static REQUEST_ID: String;
+}
+
+// Get the id of the request being handled, empty outside of a request
+pub fn current() -> String {
+    REQUEST_ID.try_with(|id| id.clone()).unwrap_or_default()
+}
+
+// Reuse the id given by a proxy in front of the server, otherwise make a new one
+fn request_id(req: &ServiceRequest) -> String {
+    let given = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 64
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    match given {
+        Some(id) => id.to_string(),
+        None => {
+            let bytes: [u8; 8] = rand::thread_rng().gen();
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+    }
+}
+
+// Middleware giving every request an id, available through current() while it is handled
+// and sent back in the X-Request-Id header
+pub fn assign<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>> + 'static
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    let id = request_id(&req);
+    let fut = REQUEST_ID.sync_scope(id.clone(), || srv.call(req));
+    REQUEST_ID.scope(id.clone(), async move {
+        let mut res = fut.await?;
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            res.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+        Ok(res)
+    })
+}
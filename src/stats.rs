@@ -0,0 +1,173 @@
+use chrono::{Duration, NaiveDate, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::metadata::MetadataStore;
+
+// Country recorded for clients without a CF-IPCountry header
+const UNKNOWN_COUNTRY: &str = "unknown";
+
+// Counters of one image on one day
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counts {
+    pub views: u64,
+    pub picks: u64,
+}
+
+// Counters not written to the store yet, keyed by UTC day
+#[derive(Debug, Default)]
+pub struct PendingStats {
+    // (day, filename, category)
+    pub images: HashMap<(String, String, String), Counts>,
+    // (day, country)
+    pub countries: HashMap<(String, String), u64>,
+}
+
+impl PendingStats {
+    fn is_empty(&self) -> bool {
+        self.images.is_empty() && self.countries.is_empty()
+    }
+
+    // Add the counters of another batch, used to keep a batch that failed to flush
+    fn merge(&mut self, other: PendingStats) {
+        for (key, counts) in other.images {
+            let entry = self.images.entry(key).or_default();
+            entry.views += counts.views;
+            entry.picks += counts.picks;
+        }
+        for (key, views) in other.countries {
+            *self.countries.entry(key).or_default() += views;
+        }
+    }
+}
+
+// View counters aggregated in memory and flushed to the metadata store periodically
+#[derive(Default)]
+pub struct ViewStats {
+    pending: Mutex<PendingStats>,
+}
+
+impl ViewStats {
+    // Count an image served to a client
+    pub fn record_view(&self, filename: &str, category: &str, country: Option<&str>) {
+        let day = today();
+        let mut pending = self.pending.lock().unwrap();
+        let key = (day.clone(), filename.to_string(), category.to_string());
+        pending.images.entry(key).or_default().views += 1;
+        let country = country.unwrap_or(UNKNOWN_COUNTRY).to_string();
+        *pending.countries.entry((day, country)).or_default() += 1;
+    }
+
+    // Count an image picked by the random endpoint
+    pub fn record_pick(&self, filename: &str, category: &str) {
+        let key = (today(), filename.to_string(), category.to_string());
+        self.pending.lock().unwrap().images.entry(key).or_default().picks += 1;
+    }
+
+    // Write the pending counters to the store, they are kept for the next flush on failure
+    pub fn flush(&self, store: &MetadataStore) {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if batch.is_empty() {
+            return;
+        }
+        if let Err(e) = store.add_stats(&batch) {
+            error!("Failed to flush view statistics: {}", e);
+            self.pending.lock().unwrap().merge(batch);
+        }
+    }
+}
+
+fn today() -> String {
+    Utc::now().date_naive().to_string()
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    // First and last UTC day of the range, the last 30 days by default
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    // Number of top images
+    pub limit: Option<usize>,
+}
+
+impl StatsQuery {
+    // Get the range of days to report on
+    pub fn range(&self) -> Result<(NaiveDate, NaiveDate), String> {
+        let to = self.to.unwrap_or_else(|| Utc::now().date_naive());
+        let from = match self.from {
+            Some(from) => from,
+            None => to
+                .checked_sub_signed(Duration::days(29))
+                .ok_or_else(|| "to is out of range.".to_string())?,
+        };
+        if from > to {
+            return Err("from must not be after to.".to_string());
+        }
+        Ok((from, to))
+    }
+
+    pub fn limit(&self) -> Result<usize, String> {
+        match self.limit.unwrap_or(10) {
+            limit @ 1..=100 => Ok(limit),
+            _ => Err("limit must be between 1 and 100.".to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImageStats {
+    pub filename: String,
+    pub category: String,
+    pub views: u64,
+    pub picks: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CategoryStats {
+    pub category: String,
+    pub views: u64,
+    pub picks: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CountryStats {
+    pub country: String,
+    pub views: u64,
+}
+
+// Aggregated counters over a range of days
+#[derive(Serialize, Debug)]
+pub struct StatsReport {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub views: u64,
+    pub picks: u64,
+    pub top_images: Vec<ImageStats>,
+    pub categories: Vec<CategoryStats>,
+    pub countries: Vec<CountryStats>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_defaults_to_30_days_and_rejects_extreme_dates() {
+        let query = StatsQuery {
+            from: None,
+            to: NaiveDate::from_ymd_opt(2024, 3, 30),
+            limit: None,
+        };
+        let (from, to) = query.range().unwrap();
+        assert_eq!(from, NaiveDate::from_ymd_opt(2024, 3, 1).unwrap());
+        assert_eq!(to, NaiveDate::from_ymd_opt(2024, 3, 30).unwrap());
+        let query = StatsQuery {
+            from: None,
+            to: Some(NaiveDate::MIN),
+            limit: None,
+        };
+        assert!(query.range().is_err());
+    }
+}
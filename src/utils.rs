@@ -1,26 +1,498 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use image::imageops::FilterType;
-use image::GenericImageView;
+use image::{GenericImageView, ImageFormat};
+use ipnet::IpNet;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::io::{BufReader, Write};
-use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::auth::Scope;
+use crate::classify::AUTO_CATEGORY;
+use crate::client_ip::ForwardedHeader;
+use crate::logging::AccessLogFormat;
+use crate::metrics::METRICS;
+use crate::random::RandomMode;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub host: String,
     pub port: u16,
     pub image_folder: String,
     pub pwd: String,
-    
+    #[serde(default = "default_categories")]
+    pub categories: Vec<Category>,
+    #[serde(default)]
+    pub renditions: RenditionConfig,
+    #[serde(default)]
+    pub cache_control: CacheControlConfig,
+    #[serde(default)]
+    pub random: RandomConfig,
+    // SQLite database with the metadata of the images
+    #[serde(default = "default_metadata_db")]
+    pub metadata_db: String,
+    #[serde(default)]
+    pub near_duplicates: NearDuplicateConfig,
+    // API tokens, the shared password remains an admin token
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    #[serde(default)]
+    pub uploads: UploadConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    #[serde(default)]
+    pub client_ip: ClientIpConfig,
+}
+
+impl Config {
+    // Check what the types alone do not, so that a bad config fails at startup
+    fn validate(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        let mut folders = HashSet::new();
+        for category in &self.categories {
+            // 'all' spans every category and 'auto' picks one on upload
+            if category.name == "all" || category.name == AUTO_CATEGORY {
+                return Err(format!("Category name '{}' is reserved.", category.name));
+            }
+            if !names.insert(category.name.as_str()) {
+                return Err(format!("Category '{}' is configured twice.", category.name));
+            }
+            let folder: PathBuf = Path::new(&category.folder)
+                .components()
+                .filter(|c| *c != Component::CurDir)
+                .collect();
+            // The watcher only sees the image folder and skips its thumbnails
+            let inside = folder.components().all(|c| matches!(c, Component::Normal(_)));
+            if !inside || folder.as_os_str().is_empty() {
+                return Err(format!(
+                    "Folder '{}' of category '{}' must be a subfolder of the image folder.",
+                    category.folder, category.name
+                ));
+            }
+            if folder == Path::new("thumbnails") {
+                return Err(format!(
+                    "Folder '{}' of category '{}' holds the thumbnails.",
+                    category.folder, category.name
+                ));
+            }
+            if !folders.insert(folder) {
+                return Err(format!(
+                    "Folder '{}' of category '{}' is used by another category.",
+                    category.folder, category.name
+                ));
+            }
+        }
+        if !matches!(self.random.redirect_status, 302 | 307) {
+            return Err(format!(
+                "random.redirect_status must be 302 or 307, not {}.",
+                self.random.redirect_status
+            ));
+        }
+        Ok(())
+    }
+}
+
+// How the address of a client is found behind proxies
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClientIpConfig {
+    // Proxies in front of the server, requests from anywhere else are taken at face value
+    pub trusted_proxies: Vec<IpNet>,
+    // The header the proxy sets, it must overwrite whatever the client sent,
+    // none to always use the address of the peer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<ForwardedHeader>,
+    // Ranges of Cloudflare, CF-IPCountry is only read from requests coming from them
+    pub cloudflare_proxies: Vec<IpNet>,
+}
+
+impl Default for ClientIpConfig {
+    fn default() -> Self {
+        ClientIpConfig {
+            trusted_proxies: ["127.0.0.0/8", "::1/128"]
+                .iter()
+                .filter_map(|net| net.parse().ok())
+                .collect(),
+            header: None,
+            cloudflare_proxies: Vec::new(),
+        }
+    }
+}
+
+impl ClientIpConfig {
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        contains(&self.trusted_proxies, ip)
+    }
+
+    pub fn is_cloudflare(&self, ip: IpAddr) -> bool {
+        contains(&self.cloudflare_proxies, ip)
+    }
+}
+
+fn contains(nets: &[IpNet], ip: IpAddr) -> bool {
+    // IPv4 clients of a dual-stack socket show up as mapped IPv6 addresses
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    };
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+// Token buckets per client IP, one for each group of routes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // Off by default, behind a proxy every client would share the buckets of the proxy until
+    // client_ip.header is set
+    pub enabled: bool,
+    // GET /api/images/{category}
+    pub random: RateLimit,
+    // /api/image/{filename} and /api/thumbnail/{filename}
+    #[serde(rename = "static")]
+    pub static_files: RateLimit,
+    // POST /api/images/{category}
+    pub upload: RateLimit,
+    // Requests with credentials answered 401, an empty bucket blocks every authenticated route
+    pub failed_auth: RateLimit,
+    // IPv6 clients sharing this prefix share their buckets, a /64 usually belongs to one host
+    pub ipv6_prefix: u8,
+    // Buckets kept in memory, the least recently used are dropped beyond it
+    pub max_clients: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            random: RateLimit { rate: 5.0, burst: 20.0 },
+            static_files: RateLimit { rate: 20.0, burst: 100.0 },
+            upload: RateLimit { rate: 0.2, burst: 10.0 },
+            failed_auth: RateLimit { rate: 0.1, burst: 5.0 },
+            ipv6_prefix: 64,
+            max_clients: 100_000,
+        }
+    }
+}
+
+// A token bucket holding up to burst requests, refilled with rate requests per second
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+// Views and random picks counted per image and day
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StatsConfig {
+    pub enabled: bool,
+    // How often the counters are written to the metadata store
+    pub flush_secs: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig {
+            enabled: true,
+            flush_secs: 60,
+        }
+    }
+}
+
+// The Prometheus endpoint on /metrics
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    // SHA-256 of the bearer secret scrapers must send, open to anyone if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_hash: Option<String>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: true,
+            secret_hash: None,
+        }
+    }
+}
+
+// Where and how much the server logs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LoggingConfig {
+    // error, warn, info, debug or trace, overridden by RUST_LOG
+    pub level: String,
+    // Log to this file instead of the console, rotated when it reaches max_file_bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub max_file_bytes: u64,
+    // Rotated files kept next to the log file
+    pub max_files: usize,
+    pub access_log: AccessLogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            file: None,
+            max_file_bytes: 10 * 1024 * 1024,
+            max_files: 5,
+            access_log: AccessLogFormat::Combined,
+        }
+    }
+}
+
+// Limits on what POST /api/images/{category} accepts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UploadConfig {
+    pub max_file_bytes: usize,
+    pub max_request_bytes: usize,
+    pub max_files: usize,
+    // Accepted input formats, sniffed from the content, e.g. jpeg, png, webp, gif
+    pub formats: Vec<String>,
+    // Largest dimensions and pixel count of an upload, checked before it is decoded
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    // Most memory the decoder may allocate for an upload
+    pub max_alloc: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            max_file_bytes: 25 * 1024 * 1024,
+            max_request_bytes: 100 * 1024 * 1024,
+            max_files: 20,
+            formats: ["jpeg", "png", "webp", "gif"]
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            max_width: 16384,
+            max_height: 16384,
+            max_pixels: 100_000_000,
+            max_alloc: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl UploadConfig {
+    // Check if a sniffed format is accepted, by any of its extensions
+    pub fn allows(&self, format: ImageFormat) -> bool {
+        self.formats
+            .iter()
+            .any(|f| format.extensions_str().contains(&f.to_lowercase().as_str()))
+    }
+}
+
+// An API token, only the hash of its secret is stored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenConfig {
+    // Shown in the logs of the requests made with the token
+    pub name: String,
+    // SHA-256 of the secret in hex, printed by the hash-token command
+    pub secret_hash: String,
+    pub scopes: Vec<Scope>,
+    // Categories the token may touch, all of them if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+fn default_metadata_db() -> String {
+    "./metadata.db".to_string()
+}
+
+// A category of images, served from a subfolder of the image folder
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Category {
+    pub name: String,
+    pub folder: String,
+    #[serde(default)]
+    pub description: String,
+    // Overrides the default mode of the random endpoint for this category
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<RandomMode>,
+    // Range of aspect ratios (width / height) the 'auto' upload target puts in this category
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_aspect: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_aspect: Option<f64>,
+}
+
+impl Category {
+    // Check if images are classified into this category by their aspect ratio
+    pub fn has_rules(&self) -> bool {
+        self.min_aspect.is_some() || self.max_aspect.is_some()
+    }
+
+    // Check if an image of the given size belongs in this category, bounds are inclusive
+    pub fn accepts(&self, width: u32, height: u32) -> bool {
+        let aspect = f64::from(width) / f64::from(height);
+        self.has_rules()
+            && self.min_aspect.is_none_or(|min| aspect >= min)
+            && self.max_aspect.is_none_or(|max| aspect <= max)
+    }
 }
 
-pub fn read_config(config_file: &str) -> Config {
+// Detection of re-encoded or resized copies by the distance between perceptual hashes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NearDuplicateConfig {
+    // Reject uploads that are near-duplicates of a stored image
+    pub reject: bool,
+    // Largest number of differing bits of the 64-bit hashes of two near-duplicates
+    pub max_distance: u32,
+}
+
+impl Default for NearDuplicateConfig {
+    fn default() -> Self {
+        NearDuplicateConfig {
+            reject: false,
+            max_distance: 6,
+        }
+    }
+}
+
+// Resized renditions requested through /api/image/{filename}?w=&h=&fit=
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RenditionConfig {
+    // Folder where generated renditions are cached, kept outside of the image folder
+    pub cache_folder: String,
+    pub max_width: u32,
+    pub max_height: u32,
+    // Requested sizes are rounded up to a multiple of the step, 0 or 1 keeps them as they are
+    pub step: u32,
+    // Allowed widths and heights, requested sizes are rounded up to one of them, overrides step
+    pub sizes: Vec<u32>,
+}
+
+impl Default for RenditionConfig {
+    fn default() -> Self {
+        RenditionConfig {
+            cache_folder: "./cache".to_string(),
+            max_width: 3840,
+            max_height: 3840,
+            step: 100,
+            sizes: Vec::new(),
+        }
+    }
+}
+
+// Cache-Control header sent by each image endpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CacheControlConfig {
+    pub image: String,
+    pub thumbnail: String,
+    // The random image changes on every request and must never be cached
+    pub random: String,
+}
+
+impl Default for CacheControlConfig {
+    fn default() -> Self {
+        CacheControlConfig {
+            image: "public, max-age=86400".to_string(),
+            thumbnail: "public, max-age=86400".to_string(),
+            random: "no-store".to_string(),
+        }
+    }
+}
+
+// Settings of the random image endpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RandomConfig {
+    // Timezone in which the hour, day and week periods roll over
+    pub timezone: Tz,
+    // Whether the random image is sent or redirected to, unless the category overrides it
+    pub mode: RandomMode,
+    // Status of the redirect, 302 or 307
+    pub redirect_status: u16,
+    pub shuffle: ShuffleConfig,
+}
+
+impl Default for RandomConfig {
+    fn default() -> Self {
+        RandomConfig {
+            timezone: Tz::UTC,
+            mode: RandomMode::Body,
+            redirect_status: 302,
+            shuffle: ShuffleConfig::default(),
+        }
+    }
+}
+
+// Shuffle bags that keep a client from getting an image twice before seeing the whole category
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShuffleConfig {
+    // Cookie identifying the client when no client is given in the query
+    pub cookie: String,
+    // Maximum number of positions kept in all bags, the least recently used clients are
+    // dropped beyond it
+    pub max_entries: usize,
+    // Seconds after which the bag of an idle client is dropped
+    pub idle_timeout: u64,
+}
+
+impl Default for ShuffleConfig {
+    fn default() -> Self {
+        ShuffleConfig {
+            cookie: "client_id".to_string(),
+            max_entries: 1_000_000,
+            idle_timeout: 86400,
+        }
+    }
+}
+
+// The categories used before they became configurable
+pub fn default_categories() -> Vec<Category> {
+    vec![
+        Category {
+            name: "pc".to_string(),
+            folder: "pc".to_string(),
+            description: "Landscape images for desktop".to_string(),
+            mode: None,
+            min_aspect: Some(1.0),
+            max_aspect: None,
+        },
+        Category {
+            name: "mp".to_string(),
+            folder: "mp".to_string(),
+            description: "Portrait images for mobile phones".to_string(),
+            mode: None,
+            min_aspect: None,
+            max_aspect: Some(1.0),
+        },
+    ]
+}
+
+pub fn read_config(config_file: &str) -> Result<Config, Box<dyn std::error::Error>> {
     match File::open(config_file) {
         Ok(file) => {
             let reader = BufReader::new(file);
-            serde_json::from_reader(reader).unwrap()
+            let config: Config = serde_json::from_reader(reader)?;
+            config.validate()?;
+            Ok(config)
         }
         Err(_) => {
             // Dump the default config to the file
@@ -29,21 +501,55 @@ pub fn read_config(config_file: &str) -> Config {
                 port: 8080,
                 image_folder: "./images".to_string(),
                 pwd: "secret".to_string(),
+                categories: default_categories(),
+                renditions: RenditionConfig::default(),
+                cache_control: CacheControlConfig::default(),
+                random: RandomConfig::default(),
+                metadata_db: default_metadata_db(),
+                near_duplicates: NearDuplicateConfig::default(),
+                tokens: Vec::new(),
+                uploads: UploadConfig::default(),
+                logging: LoggingConfig::default(),
+                metrics: MetricsConfig::default(),
+                stats: StatsConfig::default(),
+                rate_limits: RateLimitConfig::default(),
+                client_ip: ClientIpConfig::default(),
             };
-            let serialized = serde_json::to_string_pretty(&default_config).unwrap();
-            let mut file = File::create(config_file).expect("Unable to create config file");
+            let serialized = serde_json::to_string_pretty(&default_config)?;
+            let mut file = File::create(config_file)?;
             // Create the folder if it doesn't exist
-            fs::create_dir_all("./images/pc").expect("Unable to create image folder for pc");
-            fs::create_dir_all("./images/mp").expect("Unable to create image folder for mp");
-            fs::create_dir_all("./images/thumbnails").expect("Unable to create thumbnails folder");
-            file.write_all(serialized.as_bytes())
-                .expect("Unable to write to config file");
+            create_folder_structure(&default_config.image_folder, &default_config.categories)?;
+            file.write_all(serialized.as_bytes())?;
             println!("Default config created: {}", config_file);
-            default_config
+            Ok(default_config)
         }
     }
 }
 
+// Sidecar file in the image folder with the weights of the images by filename
+pub const WEIGHTS_FILE: &str = "weights.json";
+
+// Read the weights of the images, a missing or invalid file means every image weighs 1
+pub fn read_weights(image_folder: &str) -> HashMap<String, f64> {
+    let path = Path::new(image_folder).join(WEIGHTS_FILE);
+    match File::open(&path) {
+        Ok(file) => match serde_json::from_reader(BufReader::new(file)) {
+            Ok(weights) => weights,
+            Err(e) => {
+                warn!("Failed to read weights from {:?}: {}", path, e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+// Write the weights of the images to the sidecar file
+pub fn write_weights(image_folder: &str, weights: &HashMap<String, f64>) -> std::io::Result<()> {
+    let serialized = serde_json::to_string_pretty(weights)?;
+    fs::write(Path::new(image_folder).join(WEIGHTS_FILE), serialized)
+}
+
 // Create thumbnails
 pub fn create_thumbnail(
     image_path: &Path,
@@ -51,12 +557,14 @@ pub fn create_thumbnail(
     max_height: u32,
     image_folder: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let start = Instant::now();
     let thumbnails_dir = PathBuf::from(image_folder).join("thumbnails");
     // Read the image
     let img = image::open(image_path)?;
 
     // Construct the path for the thumbnail
-    let thumbnail_path = thumbnails_dir.join(image_path.file_name().unwrap());
+    let file_name = image_path.file_name().ok_or("Image path has no filename")?;
+    let thumbnail_path = thumbnails_dir.join(file_name);
 
     // Check if the thumbnail already exists
     if thumbnail_path.exists() {
@@ -81,10 +589,24 @@ pub fn create_thumbnail(
 
     // Save the thumbnail to the file
     thumbnail.save(thumbnail_path)?;
+    METRICS.thumbnail_duration.observe(start.elapsed().as_secs_f64());
 
     Ok(())
 }
 
+// Remove the thumbnail of an image, a missing thumbnail is not an error
+pub fn remove_thumbnail(image_path: &Path, image_folder: &str) -> std::io::Result<()> {
+    let file_name = match image_path.file_name() {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    let thumbnail_path = PathBuf::from(image_folder).join("thumbnails").join(file_name);
+    match fs::remove_file(thumbnail_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
 // Recursively create thumbnails
 pub fn create_thumbnails(
     folder_path: &str,
@@ -125,25 +647,25 @@ pub fn create_thumbnails(
                     // Try to create a thumbnail
                     match create_thumbnail(&path, max_width, max_height, image_folder) {
                         Ok(_) => {
-                            println!("Thumbnail created for {:?}", path);
+                            info!("Thumbnail created for {:?}", path);
                             thumbnail_count += 1;
                         }
-                        Err(e) => eprintln!("Failed to create thumbnail for {:?}: {}", path, e),
+                        Err(e) => error!("Failed to create thumbnail for {:?}: {}", path, e),
                     }
                 }
             }
         } else if path.is_dir() {
             // If the path is a directory, call the function recursively
             thumbnail_count +=
-                create_thumbnails(&path.to_str().unwrap(), max_width, max_height, image_folder)?;
+                create_thumbnails(path.to_str().unwrap(), max_width, max_height, image_folder)?;
         }
     }
     Ok(thumbnail_count)
 }
 
-// Convert the image to webp format
-pub fn convert_images_to_webp(folder_path: &str) -> std::io::Result<usize> {
-    let mut converted_count = 0;
+// Convert the image to webp format, returns the new paths with the original filenames
+pub fn convert_images_to_webp(folder_path: &str) -> std::io::Result<Vec<(PathBuf, String)>> {
+    let mut converted = Vec::new();
     // Recursively iterate through the folder
     for entry in fs::read_dir(folder_path)? {
         let entry = entry?;
@@ -153,37 +675,43 @@ pub fn convert_images_to_webp(folder_path: &str) -> std::io::Result<usize> {
         if path.is_file() {
             // Filter the files by extension
             if let Some(ext) = path.extension() {
-                if ext == "jpg" || ext == "png" || ext == "jpeg" {
-                    // Try to open the image
-                    match image::open(&path) {
-                        Ok(img) => {
-                            // Create a new path with the same name but with the webp extension
-                            let new_path = path.with_extension("webp");
-
-                            // Save the image in webp format
-                            match img.save_with_format(new_path, image::ImageFormat::WebP) {
-                                Ok(_) => {
-                                    // Remove the original image
-                                    fs::remove_file(&path)?;
-                                    println!("Converted {:?} to webp.", path);
-                                    converted_count += 1;
-                                }
-                                Err(e) => eprintln!("Failed to convert {:?}: {}", path, e),
-                            }
+                if is_convertible(ext) {
+                    match convert_image_to_webp(&path) {
+                        Ok(new_path) => {
+                            info!("Converted {:?} to webp.", path);
+                            let original = entry.file_name().to_string_lossy().to_string();
+                            converted.push((new_path, original));
                         }
-                        Err(e) => eprintln!("Failed to open {:?}: {}", path, e),
+                        Err(e) => error!("Failed to convert {:?}: {}", path, e),
                     }
                 }
             }
         } else if path.is_dir() {
             // If the path is a directory, call the function recursively
-            converted_count += convert_images_to_webp(&path.to_str().unwrap())?;
+            converted.extend(convert_images_to_webp(path.to_str().unwrap())?);
         }
     }
-    Ok(converted_count)
+    Ok(converted)
+}
+
+// Check if the extension is one of the formats converted to webp
+pub fn is_convertible(ext: &std::ffi::OsStr) -> bool {
+    ext == "jpg" || ext == "png" || ext == "jpeg"
+}
+
+// Convert a single image to webp and remove the original, returns the new path
+pub fn convert_image_to_webp(path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let img = image::open(path)?;
+    // Create a new path with the same name but with the webp extension
+    let new_path = path.with_extension("webp");
+    // Save the image in webp format
+    img.save_with_format(&new_path, image::ImageFormat::WebP)?;
+    // Remove the original image
+    fs::remove_file(path)?;
+    Ok(new_path)
 }
 
-pub fn validate_folder(folder: &str) -> std::io::Result<()> {
+pub fn validate_folder(folder: &str, categories: &[Category]) -> std::io::Result<()> {
     // Check if the folder exists
     if !Path::new(folder).exists() {
         return Err(std::io::Error::new(
@@ -191,11 +719,16 @@ pub fn validate_folder(folder: &str) -> std::io::Result<()> {
             "Image folder not found.",
         ));
     }
-    // Validate the structure of the folder, the folder should contain subfolders 'pc' and 'mp'
-    if !Path::new(&format!("{}/pc", folder)).exists()
-        || !Path::new(&format!("{}/mp", folder)).exists()
-        || !Path::new(&format!("{}/thumbnails", folder)).exists()
-    {
+    // Validate the structure of the folder, the folder should contain a subfolder for each category
+    for category in categories {
+        if !Path::new(folder).join(&category.folder).exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Folder for category '{}' not found.", category.name),
+            ));
+        }
+    }
+    if !Path::new(&format!("{}/thumbnails", folder)).exists() {
         return Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
             "Invalid image folder structure.",
@@ -204,62 +737,66 @@ pub fn validate_folder(folder: &str) -> std::io::Result<()> {
     Ok(())
 }
 
-pub fn create_folder_structure(folder: &str) -> std::io::Result<()> {
+pub fn create_folder_structure(folder: &str, categories: &[Category]) -> std::io::Result<()> {
     // Create the folder if it doesn't exist
-    fs::create_dir_all(&folder)?;
+    fs::create_dir_all(folder)?;
 
     // Create the subfolders
-    fs::create_dir_all(&format!("{}/pc", folder))?;
-    fs::create_dir_all(&format!("{}/mp", folder))?;
-    fs::create_dir_all(&format!("{}/thumbnails", folder))?;
+    for category in categories {
+        fs::create_dir_all(Path::new(folder).join(&category.folder))?;
+    }
+    fs::create_dir_all(format!("{}/thumbnails", folder))?;
 
     Ok(())
 }
 
-pub fn index_images(folder: &str) -> Vec<Vec<String>> {
-    // WalkDir::new(folder)
-    //     .into_iter()
-    //     .filter_map(|e| e.ok())
-    //     .filter(|e| {
-    //         e.path()
-    //             .extension()
-    //             .and_then(std::ffi::OsStr::to_str)
-    //             .unwrap_or("")
-    //             == "webp"
-    //     })
-    //     .map(|e| e.path().to_str().unwrap().to_string())
-    //     .collect()
-
-    // Create a vector to store the image paths excluding the thumbnails
-    let mut images = Vec::new();
-    let mut pc_images = Vec::new();
-    let mut mp_images = Vec::new();
-    // Iterate through the folder
-    for entry in WalkDir::new(folder) {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        // Check if the path is a file
-        if path.is_file() {
-            // Filter the files by extension
-            if let Some(ext) = path.extension() {
-                if ext == "webp" {
-                    let subfolder = path
-                        .parent()
-                        .unwrap()
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap();
-                    match subfolder {
-                        "pc" => pc_images.push(path.to_str().unwrap().to_string()),
-                        "mp" => mp_images.push(path.to_str().unwrap().to_string()),
-                        _ => (),
-                    }
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn category(name: &str, folder: &str) -> Category {
+        Category {
+            name: name.to_string(),
+            folder: folder.to_string(),
+            description: String::new(),
+            mode: None,
+            min_aspect: None,
+            max_aspect: None,
         }
     }
-    images.push(pc_images);
-    images.push(mp_images);
-    images
+
+    fn config(categories: Vec<Category>) -> Config {
+        let mut config: Config = serde_json::from_str(
+            r#"{"host": "127.0.0.1", "port": 8080, "image_folder": "./images", "pwd": "x"}"#,
+        )
+        .unwrap();
+        config.categories = categories;
+        config
+    }
+
+    #[test]
+    fn categories_must_be_unique_and_not_reserved() {
+        assert!(config(default_categories()).validate().is_ok());
+        assert!(config(vec![category("all", "all")]).validate().is_err());
+        assert!(config(vec![category("auto", "auto")]).validate().is_err());
+        let same_name = vec![category("pc", "pc"), category("pc", "desktop")];
+        assert!(config(same_name).validate().is_err());
+        let same_folder = vec![category("pc", "pc"), category("desktop", "./pc/")];
+        assert!(config(same_folder).validate().is_err());
+        assert!(config(vec![category("pc", "./thumbnails/")]).validate().is_err());
+        assert!(config(vec![category("pc", "../pc")]).validate().is_err());
+        assert!(config(vec![category("pc", "pc/../../pc")]).validate().is_err());
+        assert!(config(vec![category("pc", "/srv/pc")]).validate().is_err());
+        assert!(config(vec![category("pc", ".")]).validate().is_err());
+        assert!(config(vec![category("pc", "wallpapers/pc")]).validate().is_ok());
+    }
+
+    #[test]
+    fn redirect_status_must_be_302_or_307() {
+        let mut config = config(default_categories());
+        config.random.redirect_status = 307;
+        assert!(config.validate().is_ok());
+        config.random.redirect_status = 301;
+        assert!(config.validate().is_err());
+    }
 }
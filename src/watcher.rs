@@ -0,0 +1,162 @@
+use actix_web::web;
+use log::{error, info};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::RwLock;
+use std::thread;
+
+use crate::index::ImageIndex;
+use crate::metadata::MetadataStore;
+use crate::rendition::remove_renditions;
+use crate::utils::*;
+
+// Watch the image folder and keep the shared index in sync with the files on disk
+pub fn watch_images(
+    config: &Config,
+    index: web::Data<RwLock<ImageIndex>>,
+    store: web::Data<MetadataStore>,
+) -> notify::Result<()> {
+    let image_folder = &config.image_folder;
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    // Events carry absolute paths, watch the canonical folder so they can be mapped back
+    let root = fs::canonicalize(image_folder)?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+
+    let image_folder = image_folder.to_string();
+    let cache_folder = config.renditions.cache_folder.clone();
+    thread::spawn(move || {
+        // The watcher owns the sender, keep it alive for as long as events are received
+        let _watcher = watcher;
+        for res in rx {
+            match res {
+                Ok(mut event) => {
+                    // Rewrite the paths relative to the configured folder, like the index stores them
+                    for path in event.paths.iter_mut() {
+                        if let Ok(relative) = path.strip_prefix(&root) {
+                            *path = Path::new(&image_folder).join(relative);
+                        }
+                    }
+                    handle_event(event, &image_folder, &cache_folder, &index, &store)
+                }
+                Err(e) => error!("Watch error: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_event(
+    event: Event,
+    image_folder: &str,
+    cache_folder: &str,
+    index: &web::Data<RwLock<ImageIndex>>,
+    store: &MetadataStore,
+) {
+    match event.kind {
+        // Only pick up files once they are completely written or moved in
+        EventKind::Access(AccessKind::Close(AccessMode::Write))
+        | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            for path in &event.paths {
+                image_added(path, image_folder, index, store);
+            }
+        }
+        // A rename is reported as From and To and then once more as Both, only the first two
+        // are handled so that the image is not read twice
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => (),
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            for path in &event.paths {
+                image_removed(path, image_folder, cache_folder, index);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn image_added(
+    path: &Path,
+    image_folder: &str,
+    index: &web::Data<RwLock<ImageIndex>>,
+    store: &MetadataStore,
+) {
+    if in_thumbnails(path) || !path.is_file() {
+        return;
+    }
+    // Import the weights again when the sidecar file is edited
+    if path == Path::new(image_folder).join(WEIGHTS_FILE) {
+        index.write().unwrap().set_weights(read_weights(image_folder));
+        info!("Weights reloaded from {:?}", path);
+        return;
+    }
+    let ext = match path.extension() {
+        Some(ext) => ext,
+        None => return,
+    };
+    let mut original_filename = None;
+    let path: PathBuf = if is_convertible(ext) {
+        // The converted file is indexed right away, its own event is then a no-op
+        match convert_image_to_webp(path) {
+            Ok(new_path) => {
+                info!("Converted {:?} to webp.", path);
+                original_filename = path.file_name().map(|n| n.to_string_lossy().to_string());
+                new_path
+            }
+            Err(e) => {
+                error!("Failed to convert {:?}: {}", path, e);
+                return;
+            }
+        }
+    } else if ext == "webp" {
+        path.to_path_buf()
+    } else {
+        return;
+    };
+
+    if let Err(e) = create_thumbnail(&path, 200, 200, image_folder) {
+        error!("Failed to create thumbnail for {:?}: {}", path, e);
+        return;
+    }
+    if index.write().unwrap().insert(&path) {
+        info!("Indexed {:?}", path);
+    }
+    // Record the metadata of the new or replaced file
+    let category = index.read().unwrap().category_of(&path).map(str::to_string);
+    if let Some(category) = category {
+        store.record_file(&path, &category);
+        if let Some(original) = original_filename {
+            let filename = path.file_name().unwrap_or_default().to_string_lossy();
+            if let Err(e) = store.set_original_filename(&filename, &original) {
+                error!("Failed to store original filename of {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+fn image_removed(
+    path: &Path,
+    image_folder: &str,
+    cache_folder: &str,
+    index: &web::Data<RwLock<ImageIndex>>,
+) {
+    if in_thumbnails(path) {
+        return;
+    }
+    if index.write().unwrap().remove(path) {
+        info!("Removed {:?} from index", path);
+        if let Err(e) = remove_thumbnail(path, image_folder) {
+            error!("Failed to remove thumbnail for {:?}: {}", path, e);
+        }
+        if let Err(e) = remove_renditions(path, cache_folder) {
+            error!("Failed to remove renditions for {:?}: {}", path, e);
+        }
+    }
+}
+
+fn in_thumbnails(path: &Path) -> bool {
+    path.parent()
+        .and_then(|parent| parent.file_name())
+        .is_some_and(|name| name == "thumbnails")
+}